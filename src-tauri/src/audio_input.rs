@@ -0,0 +1,76 @@
+use anyhow::{anyhow, Result};
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{Device, Host};
+use serde::Serialize;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputConfig {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDevice {
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<InputConfig>,
+}
+
+// The device stored under the "inputDevice" setting, or the system default if it's unset or unplugged
+pub fn find_input_device(host: &Host, preferred: Option<&str>) -> Result<Device> {
+    if let Some(preferred) = preferred {
+        let devices = host.input_devices()?;
+        for device in devices {
+            if device.name().map_or(false, |name| name == preferred) {
+                return Ok(device);
+            }
+        }
+        eprintln!("Input device \"{}\" not found, using the default", preferred);
+    }
+
+    host.default_input_device().ok_or(anyhow!("No microphone found"))
+}
+
+#[tauri::command]
+pub fn list_input_devices() -> Result<Vec<InputDevice>, String> {
+    let host = cpal::default_host();
+    let default_name = host.default_input_device().and_then(|device| device.name().ok());
+    let devices = host.input_devices().map_err(|e| e.to_string())?;
+
+    let mut input_devices = vec![];
+    for device in devices {
+        let name = match device.name() {
+            Ok(name) => name,
+            Err(e) => {
+                eprintln!("Skipping input device without a name: {}", e);
+                continue;
+            }
+        };
+        let configs = match device.supported_input_configs() {
+            Ok(configs) => configs
+                .map(|config| InputConfig {
+                    channels: config.channels(),
+                    min_sample_rate: config.min_sample_rate().0,
+                    max_sample_rate: config.max_sample_rate().0,
+                    sample_format: config.sample_format().to_string(),
+                })
+                .collect(),
+            Err(e) => {
+                eprintln!("Failed to read configs for {}: {}", name, e);
+                vec![]
+            }
+        };
+
+        input_devices.push(InputDevice {
+            is_default: default_name.as_ref() == Some(&name),
+            name,
+            configs,
+        });
+    }
+
+    Ok(input_devices)
+}
@@ -1,112 +1,529 @@
-use std::error::Error;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, Instant};
 use std::fs::File;
-use rodio::{Decoder, OutputStream, Sink};
+use rodio::{Decoder, OutputStream, Sink, Source};
 use bytes::Bytes;
+use cpal::{FromSample, Sample};
 use std::io::{Cursor, Read};
+use std::cmp::Ordering;
+use std::ops::Range;
 use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
 use rubato::{Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction};
+use crate::echo_canceller::EchoCanceller;
 
 fn clamp(value: f32, min: f32, max: f32) -> f32 {
     value.min(max).max(min)
 }
 
-pub fn make_audio_louder(audio_samples: &[f32], gain: f32) -> Vec<f32> {
-    audio_samples
-        .iter()
-        .map(|sample| {
-            let louder_sample = sample * gain;
-            clamp(louder_sample, -1.0, 1.0)
-        })
-        .collect()
+fn db_to_amplitude(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
 }
 
-fn high_pass_filter(data: &mut Vec<f32>, cutoff: f32, sample_rate: f32) {
-    const M_PI: f32 = std::f32::consts::PI;
+// Stored under the "audioProcessing" key of the settings store
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AudioProcessingConfig {
+    // Takes the assistant's own voice out of the mic, when it's played by the app
+    pub echo_cancellation: bool,
+    pub denoise: bool,
+    pub agc: bool,
+    // The level speech is brought to, in dB below full scale
+    pub target_rms_db: f32,
+    // How quickly the gain drops when the input gets louder, and recovers when it gets quieter
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    // Stops silence being turned up into loud hiss
+    pub max_gain_db: f32,
+}
+
+impl Default for AudioProcessingConfig {
+    fn default() -> Self {
+        AudioProcessingConfig {
+            echo_cancellation: true,
+            denoise: true,
+            agc: true,
+            target_rms_db: -20.0,
+            attack_ms: 10.0,
+            release_ms: 500.0,
+            max_gain_db: 30.0,
+        }
+    }
+}
+
+// Leaves quiet samples alone and bends louder ones smoothly towards ±1, rather than clipping them
+pub fn soft_limit(sample: f32) -> f32 {
+    const KNEE: f32 = 0.5;
+    let magnitude = sample.abs();
+    if magnitude <= KNEE {
+        return sample;
+    }
+    let limited = KNEE + (1.0 - KNEE) * ((magnitude - KNEE) / (1.0 - KNEE)).tanh();
+    limited.copysign(sample)
+}
+
+// Brings every speaker to roughly the same level. The gain drops quickly when the input gets
+// louder, so loud words are caught at once, and recovers slowly so pauses aren't pumped up
+pub struct AutomaticGainControl {
+    target_rms: f32,
+    max_gain: f32,
+    attack: f32,
+    release: f32,
+    // Mean square of the last LEVEL_MS or so of input
+    level: f32,
+    level_smoothing: f32,
+    gain: f32,
+}
+
+const LEVEL_MS: f32 = 50.0;
+
+impl AutomaticGainControl {
+    pub fn new(config: &AudioProcessingConfig, sample_rate: u32) -> AutomaticGainControl {
+        // Per-sample smoothing factor for a time constant
+        let smoothing = |ms: f32| (-1.0 / (ms.max(0.1) / 1000.0 * sample_rate as f32)).exp();
+        let target_rms = db_to_amplitude(config.target_rms_db);
+        AutomaticGainControl {
+            target_rms,
+            max_gain: db_to_amplitude(config.max_gain_db),
+            attack: smoothing(config.attack_ms),
+            release: smoothing(config.release_ms),
+            level: target_rms * target_rms,
+            level_smoothing: smoothing(LEVEL_MS),
+            gain: 1.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        samples
+            .iter()
+            .map(|&sample| {
+                self.level = self.level_smoothing * self.level + (1.0 - self.level_smoothing) * sample * sample;
+                let wanted = (self.target_rms / self.level.sqrt().max(1e-6)).min(self.max_gain);
+                let smoothing = if wanted < self.gain { self.attack } else { self.release };
+                self.gain = smoothing * self.gain + (1.0 - smoothing) * wanted;
+                soft_limit(sample * self.gain)
+            })
+            .collect()
+    }
+}
+
+const DENOISE_FRAME_LEN: usize = 512;
+const DENOISE_HOP_LEN: usize = DENOISE_FRAME_LEN / 2;
+// Noise is overestimated a little so what's left doesn't sound like chirping
+const OVER_SUBTRACTION: f32 = 2.0;
+const POWER_SMOOTHING: f32 = 0.5;
+const NOISE_FRAME_RATIO: f32 = 2.0;
+// Each bin keeps at least this much of its power, for the same reason
+const SPECTRAL_FLOOR: f32 = 0.01;
+// Frames at the start assumed to be background noise, before anyone speaks
+const NOISE_LEARNING_FRAMES: usize = 10;
+
+// Spectral subtraction: the noise spectrum is learnt from the quietest parts of the input and
+// taken off every frame, so steady fans, hums and hiss drop out while speech stays
+pub struct SpectralDenoiser {
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    window: Vec<f32>,
+    // Estimated noise power in each frequency bin, and the input's power averaged over a few frames
+    noise: Vec<f32>,
+    smoothed: Vec<f32>,
+    frames_seen: usize,
+    // The last frame of input, and the output still waiting for the next frame to overlap it
+    input: Vec<f32>,
+    overlap: Vec<f32>,
+    pending: Vec<f32>,
+}
+
+impl Default for SpectralDenoiser {
+    fn default() -> Self {
+        SpectralDenoiser::new()
+    }
+}
 
-    let rc = 1.0 / (2.0 * M_PI * cutoff);
-    let dt = 1.0 / sample_rate;
-    let alpha = dt / (rc + dt);
+impl SpectralDenoiser {
+    pub fn new() -> SpectralDenoiser {
+        let mut planner = RealFftPlanner::<f32>::new();
+        // A square-root Hann window on both the way in and out adds back up to exactly the input
+        let window = (0..DENOISE_FRAME_LEN)
+            .map(|i| (std::f32::consts::PI * i as f32 / DENOISE_FRAME_LEN as f32).sin())
+            .collect();
+        SpectralDenoiser {
+            forward: planner.plan_fft_forward(DENOISE_FRAME_LEN),
+            inverse: planner.plan_fft_inverse(DENOISE_FRAME_LEN),
+            window,
+            noise: vec![0.0; DENOISE_FRAME_LEN / 2 + 1],
+            smoothed: vec![0.0; DENOISE_FRAME_LEN / 2 + 1],
+            frames_seen: 0,
+            input: vec![0.0; DENOISE_FRAME_LEN],
+            overlap: vec![0.0; DENOISE_FRAME_LEN],
+            pending: Vec::with_capacity(DENOISE_HOP_LEN * 2),
+        }
+    }
+
+    // Output lags the input by one frame, and comes out a hop at a time
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        self.pending.extend_from_slice(samples);
+        let mut output = Vec::with_capacity(self.pending.len());
+        let mut used = 0;
+        while self.pending.len() - used >= DENOISE_HOP_LEN {
+            self.input.drain(..DENOISE_HOP_LEN);
+            self.input.extend_from_slice(&self.pending[used..used + DENOISE_HOP_LEN]);
+            used += DENOISE_HOP_LEN;
+
+            let frame = self.denoise_frame();
+            for (overlap, sample) in self.overlap.iter_mut().zip(frame) {
+                *overlap += sample;
+            }
+            output.extend(self.overlap.drain(..DENOISE_HOP_LEN));
+            self.overlap.extend_from_slice(&[0.0; DENOISE_HOP_LEN]);
+        }
+        self.pending.drain(..used);
+        output
+    }
 
-    let mut y = data[0];
+    fn denoise_frame(&mut self) -> Vec<f32> {
+        let mut frame: Vec<f32> = self.input.iter().zip(&self.window).map(|(s, w)| s * w).collect();
+        let mut spectrum = self.forward.make_output_vec();
+        if let Err(e) = self.forward.process(&mut frame, &mut spectrum) {
+            eprintln!("Denoiser FFT failed: {}", e);
+            return vec![0.0; DENOISE_FRAME_LEN];
+        }
 
-    for i in 1..data.len() {
-        y = alpha * (y + data[i] - data[i - 1]);
-        data[i] = y;
+        let learning = self.frames_seen < NOISE_LEARNING_FRAMES;
+        self.frames_seen += 1;
+        // Whole frames that aren't much louder than the noise so far are probably just more of it
+        let frame_power: f32 = spectrum.iter().map(|bin| bin.norm_sqr()).sum();
+        let noise_power: f32 = self.noise.iter().sum();
+        let noise_only = frame_power < NOISE_FRAME_RATIO * noise_power;
+
+        for ((bin, noise), smoothed) in spectrum.iter_mut().zip(self.noise.iter_mut()).zip(self.smoothed.iter_mut()) {
+            let power = bin.norm_sqr();
+            // Noise power jumps around from frame to frame, which left alone turns into chirping
+            *smoothed = POWER_SMOOTHING * *smoothed + (1.0 - POWER_SMOOTHING) * power;
+            if learning {
+                *noise += power / NOISE_LEARNING_FRAMES as f32;
+            } else if noise_only {
+                *noise = 0.95 * *noise + 0.05 * power;
+            } else {
+                // Lets the estimate creep up if the room gets noisier while someone's talking
+                *noise *= 1.0005;
+            }
+
+            let gain = (1.0 - OVER_SUBTRACTION * *noise / smoothed.max(1e-12)).max(SPECTRAL_FLOOR).sqrt();
+            *bin *= gain;
+        }
+        // Rounding can leave a tiny imaginary part here, which the inverse FFT rejects
+        spectrum[0].im = 0.0;
+        spectrum[DENOISE_FRAME_LEN / 2].im = 0.0;
+
+        let mut frame = self.inverse.make_output_vec();
+        if let Err(e) = self.inverse.process(&mut spectrum, &mut frame) {
+            eprintln!("Denoiser inverse FFT failed: {}", e);
+            return vec![0.0; DENOISE_FRAME_LEN];
+        }
+        frame.iter().zip(&self.window).map(|(s, w)| s * w / DENOISE_FRAME_LEN as f32).collect()
     }
 }
 
-pub fn vad_simple(
-    mut pcmf32: &mut Vec<f32>,
-    sample_rate: usize,
-    last_ms: usize
-) -> bool {
-    let vad_thold = 0.6;
-    let freq_thold = 100.0;
+// Checks after playback starts during which the gate only learns how loud the echo is. Long enough
+// for the barge-in window to be filled with the assistant's voice
+const ECHO_GATE_WARMUP_CHECKS: usize = 5;
+// How quickly the echo level follows the mic, per check. Louder sentences are followed quickly so
+// they don't trip the gate, and pauses slowly so the level doesn't sink to the noise floor
+const ECHO_GATE_RISE: f32 = 0.5;
+const ECHO_GATE_FALL: f32 = 0.05;
+// Levels up to this fraction of the barge-in ratio are followed as the echo getting louder. Above
+// it the level is held, so the start of the user talking isn't learned as echo
+const ECHO_GATE_FOLLOW: f32 = 0.66;
+
+// While the assistant is speaking the mic picks up its voice, so rather than comparing against
+// silence we track the echo level and only report speech that is clearly louder than it.
+// Reset it whenever the assistant starts or stops speaking
+pub struct EchoGate {
+    echo_energy: f32,
+    ratio: f32,
+    warmup_checks: usize,
+}
+
+impl EchoGate {
+    pub fn new(ratio: f32) -> EchoGate {
+        EchoGate { echo_energy: 0.0, ratio, warmup_checks: 0 }
+    }
 
-    let verbose = false;
-    let n_samples = pcmf32.len();
-    let n_samples_last = (sample_rate * last_ms) / 1000;
+    // playback_running is false while the assistant's audio is still being prepared, when the mic
+    // only hears the room and learning from it would make the assistant's voice look like the user
+    pub fn is_barge_in(&mut self, recent: &[f32], playback_running: bool) -> bool {
+        if recent.is_empty() || !playback_running {
+            return false;
+        }
+        let energy = recent.iter().map(|s| s.abs()).sum::<f32>() / recent.len() as f32;
+
+        if self.warmup_checks < ECHO_GATE_WARMUP_CHECKS {
+            self.warmup_checks += 1;
+            self.echo_energy = self.echo_energy.max(energy);
+            return false;
+        }
+        if energy > self.ratio * self.echo_energy {
+            return true;
+        }
+        if energy > ECHO_GATE_FOLLOW * self.ratio * self.echo_energy {
+            return false;
+        }
 
-    if n_samples_last >= n_samples {
-        // not enough samples - assume no speech
-        return false;
+        let rate = if energy > self.echo_energy { ECHO_GATE_RISE } else { ECHO_GATE_FALL };
+        self.echo_energy += rate * (energy - self.echo_energy);
+        false
     }
 
-    if freq_thold > 0.0 {
-        high_pass_filter(&mut pcmf32, freq_thold, sample_rate as f32);
+    pub fn reset(&mut self) {
+        self.echo_energy = 0.0;
+        self.warmup_checks = 0;
     }
+}
+
+// Long enough to cover the delay from the speakers to the mic and the room's first reflections
+const ECHO_FILTER_MS: u32 = 128;
+const ECHO_STEP_SIZE: f32 = 0.5;
+
+// The mic at two points in the chain. The echo gate and VAD compare levels, so they get the audio
+// before the gain control evens them out, and only what's transcribed is levelled
+pub struct ProcessedMic {
+    pub cleaned: Vec<f32>,
+    pub levelled: Vec<f32>,
+}
+
+// The cleanup applied to the mic, in the order it has to happen: echo cancellation while the mic
+// is still a linear mix of the user and the speakers, then noise is taken out before the gain is
+// set, so it isn't turned up along with the speech
+pub struct MicProcessor {
+    echo_canceller: Option<EchoCanceller>,
+    denoiser: Option<SpectralDenoiser>,
+    agc: Option<AutomaticGainControl>,
+    sample_rate: u32,
+}
 
-    let mut energy_all = 0.0f32;
-    let mut energy_last = 0.0f32;
+impl MicProcessor {
+    pub fn new(config: &AudioProcessingConfig, sample_rate: u32) -> MicProcessor {
+        MicProcessor {
+            echo_canceller: config.echo_cancellation.then(|| EchoCanceller::new(sample_rate, ECHO_FILTER_MS, ECHO_STEP_SIZE)),
+            denoiser: config.denoise.then(SpectralDenoiser::new),
+            agc: config.agc.then(|| AutomaticGainControl::new(config, sample_rate)),
+            sample_rate,
+        }
+    }
 
-    for i in 0..n_samples {
-        energy_all += pcmf32[i].clone().abs();
+    // The reference is what was played while the mic recorded these samples
+    pub fn process(&mut self, mic: &[f32], reference: &[f32]) -> ProcessedMic {
+        let cleaned = match &mut self.echo_canceller {
+            Some(echo_canceller) => echo_canceller.process(mic, reference),
+            None => mic.to_vec(),
+        };
+        let cleaned = match &mut self.denoiser {
+            Some(denoiser) => denoiser.process(&cleaned),
+            None => cleaned,
+        };
+        let levelled = match &mut self.agc {
+            Some(agc) => agc.process(&cleaned),
+            None => cleaned.clone(),
+        };
+        ProcessedMic { cleaned, levelled }
+    }
 
-        if i >= n_samples - n_samples_last {
-            energy_last += pcmf32[i].abs();
+    // For a different mic, where the echo takes a different path
+    pub fn reset_echo(&mut self) {
+        if self.echo_canceller.is_some() {
+            self.echo_canceller = Some(EchoCanceller::new(self.sample_rate, ECHO_FILTER_MS, ECHO_STEP_SIZE));
         }
     }
+}
+
+// Averages one frame of interleaved samples, in any format cpal supports, into a mono f32 sample
+pub fn downmix_frame<T: Sample>(frame: &[T]) -> f32
+where
+    f32: FromSample<T>,
+{
+    frame.iter().map(|sample| sample.to_sample::<f32>()).sum::<f32>() / frame.len() as f32
+}
+
+// Playback older than this is dropped, in case capture has stopped taking it
+const MAX_REFERENCE_MS: usize = 2000;
+// Playback is resampled for the reference in pieces this long
+const REFERENCE_CHUNK_LEN: usize = 256;
+// Playback left over after capture has taken its share, from the device pulling audio in bursts.
+// Beyond this the oldest is dropped, so the reference never trails the mic by more than the echo
+// filter can make up for
+const MAX_REFERENCE_BACKLOG_MS: u32 = ECHO_FILTER_MS / 2;
+
+// What the app is playing, at whisper's rate, so the echo canceller can take it back out of the
+// microphone. Playback pushes samples as they're played and capture takes as many as it records.
+// Only covers TTS backends that play through a ReferenceTap. The OS voices play outside the app,
+// so there's no reference for them and their echo is left to the barge-in gate
+#[derive(Clone, Default)]
+pub struct PlaybackReference {
+    queue: Arc<Mutex<ReferenceQueue>>,
+    // Set by TTS backends that play through a ReferenceTap
+    tapped: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct ReferenceQueue {
+    samples: VecDeque<f32>,
+    last_push: Option<Instant>,
+}
+
+// Playback counts as running for this long after the last push, to cover gaps between buffers
+const PLAYBACK_IDLE_MS: u64 = 200;
+
+impl ReferenceQueue {
+    fn is_playing(&self, now: Instant) -> bool {
+        let recently_pushed = self.last_push.map_or(false, |last| now.saturating_duration_since(last) < Duration::from_millis(PLAYBACK_IDLE_MS));
+        recently_pushed || !self.samples.is_empty()
+    }
+
+    fn push(&mut self, samples: &[f32], now: Instant) {
+        self.last_push = Some(now);
+        self.samples.extend(samples);
+        let max_len = MAX_REFERENCE_MS * WHISPER_SAMPLE_RATE as usize / 1000;
+        let stale = self.samples.len().saturating_sub(max_len);
+        self.samples.drain(..stale);
+    }
+
+    fn take(&mut self, len: usize, now: Instant) -> Vec<f32> {
+        // While there's enough, the reference carries on from where the last take left it, so it
+        // stays lined up with the mic
+        let available = len.min(self.samples.len());
+        let missing = len - available;
+        // Otherwise playback has just started, stalled or finished, and it's lined up again as if the
+        // last sample pushed was played right then, with silence since. It was really played a little
+        // later and the mic heard it later still, which leaves the reference early. The filter can
+        // make up for that, where a late one couldn't be cancelled at all
+        let since_push = self.last_push.map_or(len, |last| (now.saturating_duration_since(last).as_secs_f32() * WHISPER_SAMPLE_RATE as f32) as usize);
+        let silence_after = missing.min(since_push);
+
+        let mut samples = vec![0.0; missing - silence_after];
+        samples.extend(self.samples.drain(..available));
+        samples.resize(len, 0.0);
+
+        let max_backlog = (MAX_REFERENCE_BACKLOG_MS * WHISPER_SAMPLE_RATE / 1000) as usize;
+        let stale = self.samples.len().saturating_sub(max_backlog);
+        self.samples.drain(..stale);
+        samples
+    }
+}
+
+impl PlaybackReference {
+    pub fn mark_tapped(&self) {
+        self.tapped.store(true, SeqCst);
+    }
+
+    pub fn is_tapped(&self) -> bool {
+        self.tapped.load(SeqCst)
+    }
 
-    energy_all /= n_samples as f32;
-    energy_last /= n_samples_last as f32;
+    pub fn is_playing(&self) -> bool {
+        self.queue.lock().unwrap().is_playing(Instant::now())
+    }
 
-    if verbose {
-        eprintln!(
-            "vad_simple: energy_all: {}, energy_last: {}, vad_thold: {}, freq_thold: {}",
-            energy_all, energy_last, vad_thold, freq_thold
-        );
+    pub fn push(&self, samples: &[f32]) {
+        self.queue.lock().unwrap().push(samples, Instant::now());
     }
 
-    if energy_last > vad_thold * energy_all {
-        return false;
+    // Always exactly len samples, for the len samples the mic has just recorded
+    pub fn take(&self, len: usize) -> Vec<f32> {
+        self.queue.lock().unwrap().take(len, Instant::now())
     }
+}
 
-    true
+// Plays a source unchanged while copying it, mixed down and resampled, into a PlaybackReference
+pub struct ReferenceTap<S> {
+    source: S,
+    reference: PlaybackReference,
+    // None if the source's rate can't be resampled, in which case nothing is copied
+    resampler: Option<StreamResampler>,
+    frame: Vec<f32>,
+    mono: Vec<f32>,
 }
 
-pub fn convert_stereo_to_mono_audio(samples: Vec<f32>) -> Result<Vec<f32>, &'static str> {
-    let mono_samples: Vec<f32> = samples
-        .chunks_exact(2)
-        .map(|x| (x[0] + x[1]) / 2.0)
-        .collect();
+impl<S: Source<Item = f32>> ReferenceTap<S> {
+    pub fn new(source: S, reference: PlaybackReference) -> ReferenceTap<S> {
+        let resampler = match StreamResampler::new(source.sample_rate(), WHISPER_SAMPLE_RATE) {
+            Ok(resampler) => Some(resampler),
+            Err(e) => {
+                eprintln!("Can't cancel echo of {} Hz playback: {}", source.sample_rate(), e);
+                None
+            }
+        };
+        ReferenceTap { source, reference, resampler, frame: vec![], mono: Vec::with_capacity(REFERENCE_CHUNK_LEN) }
+    }
 
-    // If there's an odd number of samples, append the last sample as is.
-    if samples.len() % 2 != 0 {
-        let last_sample = samples[samples.len() - 1];
-        return Ok([mono_samples, vec![last_sample]].concat());
+    fn flush(&mut self) {
+        if let Some(resampler) = &mut self.resampler {
+            match resampler.process(&self.mono) {
+                Ok(samples) => self.reference.push(&samples),
+                Err(e) => eprintln!("Failed to resample playback: {}", e),
+            }
+        }
+        self.mono.clear();
     }
+}
 
-    Ok(mono_samples)
+impl<S: Source<Item = f32>> Iterator for ReferenceTap<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.source.next();
+        match sample {
+            Some(sample) => {
+                self.frame.push(sample);
+                if self.frame.len() >= self.source.channels().max(1) as usize {
+                    self.mono.push(downmix_frame(&self.frame));
+                    self.frame.clear();
+                    if self.mono.len() >= REFERENCE_CHUNK_LEN {
+                        self.flush();
+                    }
+                }
+            }
+            None => self.flush(),
+        }
+        sample
+    }
 }
 
-pub fn play_audio_bytes(audio_bytes: Bytes) {
+impl<S: Source<Item = f32>> Source for ReferenceTap<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+// Pass a reference to let the echo canceller hear what's being played
+pub fn play_audio_bytes(audio_bytes: Bytes, reference: Option<&PlaybackReference>) {
     let cursor = Cursor::new(audio_bytes);
 
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     let sink = Sink::try_new(&stream_handle).unwrap();
 
     let source = Decoder::new(cursor).unwrap();
-    sink.append(source);
+    match reference {
+        Some(reference) => sink.append(ReferenceTap::new(source.convert_samples::<f32>(), reference.clone())),
+        None => sink.append(source),
+    }
 
     sink.sleep_until_end();
 }
@@ -122,47 +539,472 @@ pub fn play_audio_f32_vec(data: Vec<f32>, sample_rate: u32) {
     sink.sleep_until_end();
 }
 
-pub fn resample_audio(input: Vec<f32>, from_rate: usize, to_rate: usize) -> Result<Vec<f32>, Box<dyn Error>> {
-    let params = SincInterpolationParameters {
-        sinc_len: 256,
-        f_cutoff: 0.95,
-        oversampling_factor: 128,
-        interpolation: SincInterpolationType::Linear,
-        window: WindowFunction::BlackmanHarris2,
-    };
+// Whisper only understands 16 kHz mono audio
+pub const WHISPER_SAMPLE_RATE: u32 = 16_000;
+const RESAMPLER_CHUNK_SIZE: usize = 1024;
+
+// Converts audio from one rate to another as it arrives from the microphone. rubato needs whole
+// chunks, so whatever doesn't fill one is kept for the next call
+pub struct StreamResampler {
+    // None when the rates already match
+    resampler: Option<SincFixedIn<f32>>,
+    pending: Vec<f32>,
+}
+
+impl StreamResampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Result<StreamResampler> {
+        if from_rate == to_rate {
+            return Ok(StreamResampler { resampler: None, pending: vec![] });
+        }
+
+        let params = SincInterpolationParameters {
+            sinc_len: 256,
+            f_cutoff: 0.95,
+            oversampling_factor: 128,
+            interpolation: SincInterpolationType::Linear,
+            window: WindowFunction::BlackmanHarris2,
+        };
+        let resampler = SincFixedIn::<f32>::new(to_rate as f64 / from_rate as f64, 1.0, params, RESAMPLER_CHUNK_SIZE, 1)?;
+        Ok(StreamResampler { resampler: Some(resampler), pending: Vec::with_capacity(RESAMPLER_CHUNK_SIZE * 2) })
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>> {
+        let resampler = match &mut self.resampler {
+            Some(resampler) => resampler,
+            None => return Ok(samples.to_vec()),
+        };
+
+        self.pending.extend_from_slice(samples);
+        let mut output = vec![];
+        let mut used = 0;
+        while self.pending.len() - used >= resampler.input_frames_next() {
+            let end = used + resampler.input_frames_next();
+            let resampled = resampler.process(&[&self.pending[used..end]], None)?;
+            output.extend_from_slice(&resampled[0]);
+            used = end;
+        }
+        self.pending.drain(..used);
+        Ok(output)
+    }
+}
 
-    let mut resampler = SincFixedIn::<f32>::new(
-        to_rate as f64 / from_rate as f64,
-        10.0,
-        params,
-        1024,
-        1,
-    ).unwrap();
+// Encodes mono f32 samples as a 16-bit PCM WAV file
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+
+    for sample in samples {
+        let sample = (clamp(*sample, -1.0, 1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
 
-    let output = resampler.process(&[input], None).unwrap();
+    wav
+}
 
-    Ok(output[0].clone()) // Return the first (and only) inner vector
+// Where to look for a pause to cut long audio at, back from the longest allowed piece
+const PAUSE_SEARCH_MS: usize = 5000;
+const PAUSE_FRAME_MS: usize = 100;
+
+// Splits audio into pieces of at most max_len, cutting each at the quietest moment near its end
+// rather than mid-word, so nothing is lost or heard twice at the joins
+pub fn split_at_pauses(samples: &[f32], max_len: usize, sample_rate: u32) -> Vec<Range<usize>> {
+    let frame_len = (PAUSE_FRAME_MS * sample_rate as usize / 1000).max(1);
+    let search_len = (PAUSE_SEARCH_MS * sample_rate as usize / 1000).min(max_len / 2);
+
+    let mut pieces = vec![];
+    let mut start = 0;
+    while samples.len() - start > max_len {
+        let end = start + max_len;
+        let quietest = (end - search_len..end - frame_len + 1)
+            .step_by((frame_len / 2).max(1))
+            .map(|i| (i, samples[i..i + frame_len].iter().map(|s| s * s).sum::<f32>()))
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .map_or(end - frame_len, |(i, _)| i);
+        let cut = quietest + frame_len / 2;
+        pieces.push(start..cut);
+        start = cut;
+    }
+    if start < samples.len() {
+        pieces.push(start..samples.len());
+    }
+    pieces
 }
 
-pub fn play_audio_from_wav(path: PathBuf) {
-    let mut file = File::open(path).unwrap();
+// Blocks until the file has played
+pub fn play_audio_from_wav(path: PathBuf, reference: Option<&PlaybackReference>) -> Result<()> {
+    let mut file = File::open(path)?;
     let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    play_audio_bytes(Bytes::from(buffer));
+    file.read_to_end(&mut buffer)?;
+    play_audio_bytes(Bytes::from(buffer), reference);
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs::File;
-    use std::io::Read;
+    use std::io::{BufReader, Read};
+    use std::time::{Duration, Instant};
     use bytes::Bytes;
-    use crate::audio_utils::play_audio_bytes;
+    use std::f32::consts::PI;
+    use rodio::{Decoder, Source};
+    use crate::audio_utils::{downmix_frame, play_audio_bytes, soft_limit, split_at_pauses, PlaybackReference, ReferenceTap, AudioProcessingConfig, AutomaticGainControl, EchoGate, MicProcessor, ProcessedMic, SpectralDenoiser, StreamResampler, ECHO_FILTER_MS, ECHO_STEP_SIZE, WHISPER_SAMPLE_RATE};
+    use crate::echo_canceller::EchoCanceller;
+
+    fn sine(frequency: f32, sample_rate: u32, seconds: f32, amplitude: f32) -> Vec<f32> {
+        (0..(sample_rate as f32 * seconds) as usize)
+            .map(|i| (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin() * amplitude)
+            .collect()
+    }
+
+    // Power of a single frequency, like one bin of a DFT
+    fn tone_power(samples: &[f32], frequency: f32, sample_rate: u32) -> f32 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, sample) in samples.iter().enumerate() {
+            let phase = 2.0 * PI * frequency * i as f32 / sample_rate as f32;
+            re += sample * phase.cos();
+            im += sample * phase.sin();
+        }
+        (re * re + im * im) / samples.len() as f32
+    }
+
+    // Feeds the audio in uneven pieces, like the capture loop does
+    fn resample(samples: &[f32], from_rate: u32) -> Vec<f32> {
+        let mut resampler = StreamResampler::new(from_rate, WHISPER_SAMPLE_RATE).unwrap();
+        samples.chunks(777).flat_map(|chunk| resampler.process(chunk).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_resampled_length() {
+        for from_rate in [16_000, 22_050, 44_100, 48_000] {
+            let output = resample(&vec![0.0; from_rate as usize * 2], from_rate);
+            // Up to one chunk is still waiting in the resampler
+            let expected = WHISPER_SAMPLE_RATE as usize * 2;
+            assert!(output.len() <= expected && output.len() + 1024 >= expected, "{} Hz gave {} samples", from_rate, output.len());
+        }
+    }
+
+    #[test]
+    fn test_resampled_spectrum() {
+        // A 1 kHz tone should survive, a 10 kHz one is above what 16 kHz can hold and must not alias down to 6 kHz
+        let speech: Vec<f32> = sine(1000.0, 44_100, 1.0, 0.5);
+        let whistle: Vec<f32> = sine(10_000.0, 44_100, 1.0, 0.5);
+        let mixed: Vec<f32> = speech.iter().zip(&whistle).map(|(a, b)| a + b).collect();
+
+        let output = resample(&mixed, 44_100);
+        // Skip the filter's start up
+        let output = &output[1000..];
+        let kept = tone_power(output, 1000.0, WHISPER_SAMPLE_RATE);
+        assert!(kept > 100.0 * tone_power(output, 2000.0, WHISPER_SAMPLE_RATE));
+        assert!(kept > 1000.0 * tone_power(output, 6000.0, WHISPER_SAMPLE_RATE));
+        // Same loudness as before resampling, within 10%
+        let original = tone_power(&speech[2756..], 1000.0, 44_100) * (output.len() as f32 / speech[2756..].len() as f32);
+        assert!((kept / original - 1.0).abs() < 0.1, "{} vs {}", kept, original);
+    }
+
+    #[test]
+    fn test_split_at_pauses() {
+        // Speech with a short pause 27.5 seconds in, just inside the first 30 second piece
+        let mut samples = sine(200.0, 16_000, 27.5, 0.5);
+        samples.extend(vec![0.0; 1600]);
+        samples.extend(sine(200.0, 16_000, 20.0, 0.5));
+
+        let pieces = split_at_pauses(&samples, 30 * 16_000, 16_000);
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0].start, 0);
+        assert_eq!(pieces[1].end, samples.len());
+        assert_eq!(pieces[0].end, pieces[1].start);
+        let cut = pieces[0].end;
+        assert!(cut >= 27 * 16_000 + 8000 && cut <= 27 * 16_000 + 8000 + 1600, "cut at {}", cut);
+
+        // Short audio is left alone, and no pause still keeps every piece short enough
+        assert_eq!(split_at_pauses(&samples[..16_000], 30 * 16_000, 16_000), vec![0..16_000]);
+        let pieces = split_at_pauses(&noise(95 * 16_000, 0.5), 30 * 16_000, 16_000);
+        assert!(pieces.iter().all(|piece| piece.len() <= 30 * 16_000 && !piece.is_empty()));
+        assert_eq!(pieces.last().unwrap().end, 95 * 16_000);
+        assert!(split_at_pauses(&[], 30 * 16_000, 16_000).is_empty());
+    }
+
+    #[test]
+    fn test_playback_reference() {
+        let reference = PlaybackReference::default();
+        assert_eq!(reference.take(3), vec![0.0; 3]);
+
+        let clock = Instant::now();
+        let at = |ms: u64| clock + Duration::from_millis(ms);
+        let mut queue = reference.queue.lock().unwrap();
+        // Playback started partway through what the mic recorded
+        queue.push(&[0.1, 0.2, 0.3], at(0));
+        assert_eq!(queue.take(4, at(0)), vec![0.0, 0.1, 0.2, 0.3]);
+        // While there's enough it carries on from where it left off, with a short backlog
+        queue.push(&vec![0.5; 4000], at(10));
+        assert_eq!(queue.take(1600, at(100)), vec![0.5; 1600]);
+        assert_eq!(queue.samples.len(), 1024);
+        assert!(queue.is_playing(at(100)));
+        // Playback finished with the last push, so what's left is followed by silence
+        let mut finished = vec![0.5; 1024];
+        finished.resize(1600, 0.0);
+        assert_eq!(queue.take(1600, at(200)), finished);
+        assert!(!queue.is_playing(at(300)));
+
+        // Nobody taking it doesn't let it grow forever
+        queue.push(&vec![0.5; WHISPER_SAMPLE_RATE as usize * 10], at(1000));
+        assert_eq!(queue.take(WHISPER_SAMPLE_RATE as usize * 3, at(1000)).iter().filter(|s| **s == 0.5).count(), WHISPER_SAMPLE_RATE as usize * 2);
+    }
+
+    // The session complete chime, as a ReferenceTap copies it while it's played
+    fn recorded_chime() -> Vec<f32> {
+        let reference = PlaybackReference::default();
+        let file = BufReader::new(File::open("assets/audio/session_complete.wav").unwrap());
+        for _ in ReferenceTap::new(Decoder::new(file).unwrap().convert_samples::<f32>(), reference.clone()) {}
+        let samples = reference.queue.lock().unwrap().samples.drain(..).collect();
+        samples
+    }
+
+    #[test]
+    fn test_reference_stays_lined_up_with_the_mic() {
+        let chime = recorded_chime();
+        let rate = WHISPER_SAMPLE_RATE as usize;
+        let step = rate / 100;
+        let clock = Instant::now();
+        let at = |sample: usize| clock + Duration::from_secs_f64(sample as f64 / rate as f64);
+        // The chime is played twice, starting partway between reads of the mic
+        let sound = [chime.clone(), chime.clone()].concat();
+        let starts = [rate * 37 / 100, rate * 52 / 100 + chime.len()];
+
+        let reference = PlaybackReference::default();
+        let mut queue = reference.queue.lock().unwrap();
+        let mut canceller = EchoCanceller::new(WHISPER_SAMPLE_RATE, ECHO_FILTER_MS, ECHO_STEP_SIZE);
+        let (mut pulled, mut played, mut speaker, mut mic) = (0, 0, vec![], vec![]);
+        let (mut heard, mut left) = (0.0, 0.0);
+        for k in 0..(starts[1] + chime.len() + rate / 5) / step {
+            let now = k * step;
+            // The device keeps 20 to 40ms queued, topping it up every 10ms, apart from a 60ms stall
+            let queued = if now >= starts[1] { sound.len() } else if now >= starts[0] { chime.len() } else { 0 };
+            if !(84..90).contains(&k) {
+                let wanted = (played + [320, 480, 640, 400][k % 4]).min(queued);
+                if wanted > pulled {
+                    queue.push(&sound[pulled..wanted], at(now));
+                    pulled = wanted;
+                }
+            }
+            // It plays what it has pulled, and silence once that runs out
+            for _ in 0..step {
+                speaker.push(if played < pulled { sound[played] } else { 0.0 });
+                played = (played + 1).min(pulled);
+            }
+            // The mic hears it 5ms later, with a reflection after 15ms
+            for i in now..now + step {
+                let out = |delay: usize| i.checked_sub(delay).map_or(0.0, |j| speaker[j]);
+                mic.push(0.6 * out(80) + 0.3 * out(240));
+            }
+
+            // Captured every 100ms, with some held back like the resampler does
+            if k % 10 == 9 {
+                let len = mic.len() - [0, 300][k / 10 % 2];
+                let first = now + step - mic.len();
+                let cleaned = canceller.process(&mic[..len], &queue.take(len, at(now + step)));
+                // Once the filter has had half a second to learn the room
+                if first >= starts[0] + rate / 2 {
+                    heard += mic[..len].iter().map(|s| s * s).sum::<f32>();
+                    left += cleaned.iter().map(|s| s * s).sum::<f32>();
+                }
+                mic.drain(..len);
+            }
+        }
+        let erle = 10.0 * (heard / left).log10();
+        assert!(erle > 8.0, "echo only reduced by {} dB", erle);
+    }
+
+    // One barge-in check per 100ms of a 300ms window, like the capture loop, at a steady level
+    fn gate_checks(gate: &mut EchoGate, recent: &mut Vec<f32>, amplitude: f32, checks: usize, playback_running: bool) -> usize {
+        let mut barge_ins = 0;
+        for _ in 0..checks {
+            recent.extend(sine(200.0, WHISPER_SAMPLE_RATE, 0.1, amplitude));
+            let stale = recent.len().saturating_sub(4800);
+            recent.drain(..stale);
+            if gate.is_barge_in(recent, playback_running) {
+                barge_ins += 1;
+            }
+        }
+        barge_ins
+    }
+
+    #[test]
+    fn test_echo_gate_learns_echo_once_playback_runs() {
+        let mut gate = EchoGate::new(3.0);
+        let mut recent = vec![];
+        // Room noise while the reply is being synthesized isn't taken as the echo level
+        assert_eq!(gate_checks(&mut gate, &mut recent, 0.01, 10, false), 0);
+        // So the assistant's voice at 10x the noise doesn't interrupt itself
+        assert_eq!(gate_checks(&mut gate, &mut recent, 0.1, 20, true), 0);
+        // The user talking well over it does
+        assert!(gate_checks(&mut gate, &mut recent, 0.5, 5, true) >= 3);
+    }
+
+    #[test]
+    fn test_echo_gate_follows_louder_echo() {
+        let mut gate = EchoGate::new(3.0);
+        let mut recent = vec![];
+        let mut amplitude = 0.02;
+        // A sentence that gets gradually louder, to 8x where it started
+        for _ in 0..6 {
+            assert_eq!(gate_checks(&mut gate, &mut recent, amplitude, 5, true), 0, "{}", amplitude);
+            amplitude *= 1.5;
+        }
+        // A pause between sentences doesn't drop the level to the floor
+        gate_checks(&mut gate, &mut recent, 0.001, 5, true);
+        assert_eq!(gate_checks(&mut gate, &mut recent, amplitude, 5, true), 0);
+    }
+
+    #[test]
+    fn test_echo_gate_reset() {
+        let mut gate = EchoGate::new(3.0);
+        let mut recent = vec![];
+        gate_checks(&mut gate, &mut recent, 0.5, 10, true);
+        gate.reset();
+        recent.clear();
+        // A quieter voice after the reset is learned again rather than compared to the old level
+        assert_eq!(gate_checks(&mut gate, &mut recent, 0.05, 10, true), 0);
+        assert!(gate_checks(&mut gate, &mut recent, 0.3, 5, true) >= 3);
+    }
+
+    #[test]
+    fn test_barge_in_through_the_whole_chain() {
+        // A steady echo of an OS voice, which has no reference to cancel it with, then the user
+        // talking loudly over it
+        let rate = WHISPER_SAMPLE_RATE as usize;
+        let echo = noise(rate * 6, 0.1);
+        let user = sine(300.0, WHISPER_SAMPLE_RATE, 6.0, 0.5);
+        let mic: Vec<f32> = (0..rate * 6).map(|i| if i >= rate * 4 { echo[i] + user[i] } else { echo[i] }).collect();
+        let reference = vec![0.0; rate * 6];
+
+        let mut processor = MicProcessor::new(&AudioProcessingConfig::default(), WHISPER_SAMPLE_RATE);
+        let mut gate = EchoGate::new(3.0);
+        let mut recent = vec![];
+        let (mut echo_barge_ins, mut user_barge_ins, mut transcribed) = (0, 0, vec![]);
+        // 100ms at a time, like the capture loop
+        for start in (0..rate * 6).step_by(rate / 10) {
+            let ProcessedMic { cleaned, levelled } = processor.process(&mic[start..start + rate / 10], &reference[start..start + rate / 10]);
+            assert_eq!(cleaned.len(), levelled.len());
+            recent.extend(cleaned);
+            let stale = recent.len().saturating_sub(rate * 3 / 10);
+            recent.drain(..stale);
+
+            let barge_in = gate.is_barge_in(&recent, true);
+            if start < rate * 4 && barge_in {
+                echo_barge_ins += 1;
+            }
+            // Within the first half second of the user talking
+            if (rate * 4..rate * 9 / 2).contains(&start) && barge_in {
+                user_barge_ins += 1;
+            }
+            if start >= rate * 5 {
+                transcribed.extend(levelled);
+            }
+        }
+        assert_eq!(echo_barge_ins, 0);
+        assert!(user_barge_ins >= 3, "{} barge-in checks", user_barge_ins);
+        // What's transcribed is still brought to the target level
+        let target = 10.0_f32.powf(AudioProcessingConfig::default().target_rms_db / 20.0);
+        assert!((20.0 * (rms(&transcribed) / target).log10()).abs() < 3.0, "transcribed at {}", rms(&transcribed));
+    }
+
+    #[test]
+    fn test_downmix_frame() {
+        assert_eq!(downmix_frame(&[0.25f32]), 0.25);
+        assert_eq!(downmix_frame(&[0.5f32, -0.5, 1.0, 1.0]), 0.5);
+        assert_eq!(downmix_frame(&[i16::MIN, 0]), -0.5);
+        assert_eq!(downmix_frame(&[u16::MAX / 2 + 1, u16::MAX / 2 + 1]), 0.0);
+        assert_eq!(downmix_frame(&[0.5f64, 0.0]), 0.25);
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    // Repeatable white noise between -amplitude and amplitude
+    fn noise(len: usize, amplitude: f32) -> Vec<f32> {
+        let mut state: u32 = 12345;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                ((state >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_soft_limit() {
+        assert_eq!(soft_limit(0.3), 0.3);
+        assert_eq!(soft_limit(-0.5), -0.5);
+        assert!(soft_limit(0.9) > 0.8 && soft_limit(0.9) < 0.9);
+        assert!(soft_limit(10.0) <= 1.0 && soft_limit(-10.0) >= -1.0);
+        assert!(soft_limit(0.7) < soft_limit(0.8));
+    }
+
+    #[test]
+    fn test_agc_levels_quiet_and_loud_speakers() {
+        let config = AudioProcessingConfig::default();
+        let target = 10.0_f32.powf(config.target_rms_db / 20.0);
+        for amplitude in [0.01, 0.1, 0.9] {
+            let mut agc = AutomaticGainControl::new(&config, 16_000);
+            let output = agc.process(&sine(200.0, 16_000, 3.0, amplitude));
+            let level = rms(&output[32_000..]);
+            assert!((20.0 * (level / target).log10()).abs() < 3.0, "{} came out at {}", amplitude, level);
+            assert!(output.iter().all(|s| s.abs() <= 1.0));
+        }
+    }
+
+    #[test]
+    fn test_agc_does_not_boost_silence_past_max_gain() {
+        let config = AudioProcessingConfig::default();
+        let mut agc = AutomaticGainControl::new(&config, 16_000);
+        let output = agc.process(&noise(32_000, 0.0001));
+        assert!(rms(&output[16_000..]) < 0.0001 * 10.0_f32.powf(config.max_gain_db / 20.0));
+    }
+
+    #[test]
+    fn test_denoiser_removes_steady_noise_and_keeps_speech() {
+        let mut denoiser = SpectralDenoiser::new();
+        let hiss = noise(32_000, 0.05);
+        let tone = sine(440.0, 16_000, 1.0, 0.3);
+        // A second of background noise, then a tone on top of it
+        let mut input = hiss[..16_000].to_vec();
+        input.extend(tone.iter().zip(&hiss[16_000..]).map(|(t, n)| t + n));
+
+        let output: Vec<f32> = input.chunks(1600).flat_map(|chunk| denoiser.process(chunk)).collect();
+        assert!(output.len() <= input.len() && output.len() + 512 >= input.len());
+
+        let noise_only = &output[8_000..16_000];
+        assert!(rms(noise_only) < 0.3 * rms(&hiss[8_000..16_000]), "{}", rms(noise_only));
+
+        // The tone comes through at about the same strength, with less around it
+        let (before, after) = (&input[20_000..30_000], &output[20_000..30_000]);
+        assert!(tone_power(after, 440.0, 16_000) > 0.7 * tone_power(before, 440.0, 16_000));
+        let tone_share = |s: &[f32]| 2.0 * tone_power(s, 440.0, 16_000) / s.iter().map(|x| x * x).sum::<f32>();
+        assert!(tone_share(after) > tone_share(before));
+        assert!(tone_share(after) > 0.9, "{}", tone_share(after));
+    }
 
     #[test]
     fn test_play_audio() {
         let mut file = File::open("../assets/audio/test.wav").unwrap();
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer).unwrap();
-        play_audio_bytes(Bytes::from(buffer));
+        play_audio_bytes(Bytes::from(buffer), None);
     }
 }
@@ -0,0 +1,305 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::types::{ChatCompletionFunctions, ChatCompletionRequestMessage, CreateChatCompletionRequest, CreateChatCompletionRequestArgs, Role};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use tauri::AppHandle;
+use crate::stores::get_setting;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChatReply {
+    Message(String),
+    FunctionCall { name: String, arguments: String },
+}
+
+// Streamed replies yield `Message` fragments as they arrive and at most one complete `FunctionCall`
+pub type ChatStream = BoxStream<'static, Result<ChatReply>>;
+
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn complete(&self, messages: Vec<ChatCompletionRequestMessage>, functions: Vec<ChatCompletionFunctions>) -> Result<ChatReply>;
+
+    // Providers without streaming support return the whole reply as a single item
+    async fn complete_stream(&self, messages: Vec<ChatCompletionRequestMessage>, functions: Vec<ChatCompletionFunctions>) -> Result<ChatStream> {
+        let reply = self.complete(messages, functions).await?;
+        Ok(futures::stream::once(async { Ok(reply) }).boxed())
+    }
+}
+
+// Selects the chat provider from the "llmProvider" setting, defaulting to OpenAI
+pub fn from_settings(handle: &AppHandle) -> Result<Box<dyn ChatProvider>> {
+    let provider = get_setting::<String>(handle.clone(), "llmProvider").unwrap_or("openai".to_string());
+    let base_url = get_setting::<String>(handle.clone(), "llmBaseUrl");
+    let model = get_setting::<String>(handle.clone(), "llmModel");
+
+    match provider.as_str() {
+        "openai" => {
+            let api_key = get_setting::<String>(handle.clone(), "llmApiKey")
+                .or_else(|| env::var("OPENAI_API_KEY").ok())
+                .ok_or(anyhow!("No API key set for the OpenAI provider"))?;
+            Ok(Box::new(OpenAiProvider::new(
+                base_url.unwrap_or("https://api.openai.com/v1".to_string()),
+                api_key,
+                model.unwrap_or("gpt-3.5-turbo".to_string()),
+            )))
+        }
+        "local" => Ok(Box::new(LocalServerProvider::new(
+            base_url.unwrap_or("http://localhost:11434".to_string()),
+            model.unwrap_or("llama2".to_string()),
+        ))),
+        "mock" => {
+            let script = get_setting::<Vec<String>>(handle.clone(), "llmMockScript").unwrap_or_default();
+            Ok(Box::new(ScriptedProvider::from_script(script)))
+        }
+        other => Err(anyhow!("Unknown LLM provider: {}", other)),
+    }
+}
+
+// Any endpoint that speaks the OpenAI chat completions API
+pub struct OpenAiProvider {
+    client: Client<OpenAIConfig>,
+    model: String,
+}
+
+impl OpenAiProvider {
+    pub fn new(base_url: String, api_key: String, model: String) -> OpenAiProvider {
+        let config = OpenAIConfig::new()
+            .with_api_base(base_url.trim_end_matches('/'))
+            .with_api_key(api_key);
+
+        OpenAiProvider { client: Client::with_config(config), model }
+    }
+
+    fn request(&self, messages: Vec<ChatCompletionRequestMessage>, functions: Vec<ChatCompletionFunctions>) -> Result<CreateChatCompletionRequest> {
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request
+            .model(self.model.clone())
+            .max_tokens(120_u16)
+            .messages(messages);
+        if !functions.is_empty() {
+            request.functions(functions);
+        }
+        Ok(request.build()?)
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAiProvider {
+    async fn complete(&self, messages: Vec<ChatCompletionRequestMessage>, functions: Vec<ChatCompletionFunctions>) -> Result<ChatReply> {
+        let resp = self.client.chat().create(self.request(messages, functions)?).await?;
+        let resp_message = resp.choices.get(0).ok_or(anyhow!("No choices in chat completion"))?.message.clone();
+
+        if let Some(function_call) = resp_message.function_call {
+            return Ok(ChatReply::FunctionCall { name: function_call.name, arguments: function_call.arguments });
+        }
+
+        Ok(ChatReply::Message(resp_message.content.unwrap_or_default()))
+    }
+
+    async fn complete_stream(&self, messages: Vec<ChatCompletionRequestMessage>, functions: Vec<ChatCompletionFunctions>) -> Result<ChatStream> {
+        let mut stream = self.client.chat().create_stream(self.request(messages, functions)?).await?;
+
+        Ok(Box::pin(async_stream::try_stream! {
+            // Function calls arrive in pieces, so they are only yielded once the stream ends
+            let mut function_name = String::new();
+            let mut function_arguments = String::new();
+
+            while let Some(response) = stream.next().await {
+                for choice in response?.choices {
+                    if let Some(content) = choice.delta.content {
+                        yield ChatReply::Message(content);
+                    }
+                    if let Some(function_call) = choice.delta.function_call {
+                        function_name.push_str(&function_call.name.unwrap_or_default());
+                        function_arguments.push_str(&function_call.arguments.unwrap_or_default());
+                    }
+                }
+            }
+
+            if !function_name.is_empty() {
+                yield ChatReply::FunctionCall { name: function_name, arguments: function_arguments };
+            }
+        }))
+    }
+}
+
+// Ollama-style /api/chat server (llama.cpp and friends behind an Ollama-compatible API).
+// These servers have no function calling, so the functions are described in a system
+// message and the model is asked to answer with a single `CALL <name> <json>` line instead.
+pub struct LocalServerProvider {
+    client: reqwest::Client,
+    base_url: String,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct LocalChatResponse {
+    message: LocalChatMessage,
+}
+
+#[derive(Deserialize)]
+struct LocalChatMessage {
+    content: String,
+}
+
+impl LocalServerProvider {
+    pub fn new(base_url: String, model: String) -> LocalServerProvider {
+        LocalServerProvider {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model,
+        }
+    }
+}
+
+fn local_role(role: &Role) -> &'static str {
+    match role {
+        Role::System => "system",
+        Role::User => "user",
+        Role::Assistant => "assistant",
+        Role::Function => "tool",
+    }
+}
+
+// The model has no function calling, so its calls go back in the `CALL` syntax it answered with,
+// and each result says which call it is for
+fn local_message(message: &ChatCompletionRequestMessage) -> serde_json::Value {
+    let content = message.content.clone().unwrap_or_default();
+    let content = match (&message.role, &message.function_call, &message.name) {
+        (Role::Assistant, Some(call), _) => format!("{}\nCALL {} {}", content, call.name, call.arguments).trim().to_string(),
+        (Role::Function, _, Some(name)) => format!("Result of {}: {}", name, content),
+        _ => content,
+    };
+    json!({"role": local_role(&message.role), "content": content})
+}
+
+fn describe_functions(functions: &[ChatCompletionFunctions]) -> String {
+    let mut description = "You can call a function by replying with a single line of the form `CALL <name> <json arguments>` and nothing else. Available functions:".to_string();
+    for function in functions {
+        description.push_str(&format!(
+            "\n- {}: {} Parameters: {}",
+            function.name,
+            function.description.clone().unwrap_or_default(),
+            function.parameters.clone().unwrap_or(json!({})),
+        ));
+    }
+    description
+}
+
+pub fn parse_local_reply(content: &str) -> ChatReply {
+    let trimmed = content.trim();
+    if let Some(call) = trimmed.strip_prefix("CALL ") {
+        let mut parts = call.trim().splitn(2, char::is_whitespace);
+        if let Some(name) = parts.next().filter(|name| !name.is_empty()) {
+            let arguments = parts.next().unwrap_or("{}").trim().to_string();
+            return ChatReply::FunctionCall { name: name.to_string(), arguments };
+        }
+    }
+    ChatReply::Message(trimmed.to_string())
+}
+
+#[async_trait]
+impl ChatProvider for LocalServerProvider {
+    async fn complete(&self, messages: Vec<ChatCompletionRequestMessage>, functions: Vec<ChatCompletionFunctions>) -> Result<ChatReply> {
+        let mut local_messages: Vec<serde_json::Value> = messages
+            .iter()
+            .map(local_message)
+            .collect();
+        if !functions.is_empty() {
+            local_messages.insert(0, json!({"role": "system", "content": describe_functions(&functions)}));
+        }
+
+        let body = json!({
+            "model": self.model,
+            "messages": local_messages,
+            "stream": false,
+        });
+
+        let response: LocalChatResponse = self.client
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(parse_local_reply(&response.message.content))
+    }
+}
+
+// Plays back a fixed list of replies so the conversation loop can run offline
+pub struct ScriptedProvider {
+    replies: Mutex<VecDeque<ChatReply>>,
+}
+
+impl ScriptedProvider {
+    pub fn new(replies: Vec<ChatReply>) -> ScriptedProvider {
+        ScriptedProvider { replies: Mutex::new(replies.into()) }
+    }
+
+    // Script lines use the same `CALL <name> <json>` syntax as the local provider
+    pub fn from_script(script: Vec<String>) -> ScriptedProvider {
+        ScriptedProvider::new(script.iter().map(|line| parse_local_reply(line)).collect())
+    }
+}
+
+#[async_trait]
+impl ChatProvider for ScriptedProvider {
+    async fn complete(&self, _messages: Vec<ChatCompletionRequestMessage>, _functions: Vec<ChatCompletionFunctions>) -> Result<ChatReply> {
+        let reply = self.replies.lock().unwrap().pop_front();
+        Ok(reply.unwrap_or(ChatReply::FunctionCall { name: "leave_conversation".to_string(), arguments: "{}".to_string() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::{ChatCompletionRequestMessageArgs, FunctionCall, Role};
+    use serde_json::json;
+    use crate::chat_provider::{local_message, parse_local_reply, ChatReply};
+    use crate::gpt::create_function_result_msg;
+
+    #[test]
+    fn test_parse_local_reply() {
+        assert_eq!(parse_local_reply(" Great job! "), ChatReply::Message("Great job!".to_string()));
+        assert_eq!(
+            parse_local_reply("CALL leave_conversation {}"),
+            ChatReply::FunctionCall { name: "leave_conversation".to_string(), arguments: "{}".to_string() }
+        );
+        assert_eq!(
+            parse_local_reply("CALL leave_conversation"),
+            ChatReply::FunctionCall { name: "leave_conversation".to_string(), arguments: "{}".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_local_function_round() {
+        let call = ChatCompletionRequestMessageArgs::default()
+            .role(Role::Assistant)
+            .function_call(FunctionCall { name: "mark_step_complete".to_string(), arguments: r#"{"step_id":"step-1"}"#.to_string() })
+            .build()
+            .unwrap();
+        assert_eq!(
+            local_message(&call),
+            json!({"role": "assistant", "content": r#"CALL mark_step_complete {"step_id":"step-1"}"#})
+        );
+        // What the model said back is parsed the same way
+        assert_eq!(
+            parse_local_reply(local_message(&call)["content"].as_str().unwrap()),
+            ChatReply::FunctionCall { name: "mark_step_complete".to_string(), arguments: r#"{"step_id":"step-1"}"#.to_string() }
+        );
+
+        let result = create_function_result_msg("mark_step_complete", "Marked Shower as completed".to_string()).unwrap();
+        assert_eq!(
+            local_message(&result),
+            json!({"role": "tool", "content": "Result of mark_step_complete: Marked Shower as completed"})
+        );
+    }
+}
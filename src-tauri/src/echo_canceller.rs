@@ -0,0 +1,158 @@
+// Mic samples louder than anything recently played can't be echo, so they're the user talking
+// over the assistant. The filter stops learning then, or it would learn to cancel the user
+const DOUBLE_TALK_RATIO: f32 = 1.0;
+// How long learning stays off after the user was last heard over playback
+const DOUBLE_TALK_HOLD_MS: u32 = 50;
+// Keeps the step finite when the reference is nearly silent
+const REGULARIZATION: f32 = 1e-6;
+
+// Removes the app's own playback from the microphone signal with an NLMS adaptive filter. The
+// filter learns how the speaker's sound reaches the mic (delay, room reflections, volume) from
+// the reference signal and subtracts its prediction of that echo
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    // The reference written twice, so the newest weights.len() samples are always one slice
+    history: Vec<f32>,
+    position: usize,
+    // Sum of squares of the reference in the history. Kept in f64, since in f32 the rounding left
+    // behind by loud playback can outweigh the quiet that follows it and make the step blow up
+    energy: f64,
+    step_size: f32,
+    double_talk_hold: usize,
+    double_talk_remaining: usize,
+}
+
+impl EchoCanceller {
+    // The filter has to be longer than the delay between playing a sample and the mic hearing it
+    pub fn new(sample_rate: u32, filter_ms: u32, step_size: f32) -> EchoCanceller {
+        let filter_len = (sample_rate * filter_ms / 1000).max(1) as usize;
+        EchoCanceller {
+            weights: vec![0.0; filter_len],
+            history: vec![0.0; filter_len * 2],
+            position: 0,
+            energy: 0.0,
+            step_size,
+            double_talk_hold: (sample_rate * DOUBLE_TALK_HOLD_MS / 1000) as usize,
+            double_talk_remaining: 0,
+        }
+    }
+
+    // The reference is what was played while the mic recorded these samples. Missing reference
+    // samples are taken as silence
+    pub fn process(&mut self, mic: &[f32], reference: &[f32]) -> Vec<f32> {
+        let len = self.weights.len();
+        let peak = |samples: &[f32]| samples.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        let reference_peak = peak(&self.history[self.position..self.position + len]).max(peak(reference));
+
+        mic.iter()
+            .enumerate()
+            .map(|(i, &sample)| {
+                let newest = reference.get(i).copied().unwrap_or(0.0);
+                let oldest = self.history[self.position + len - 1];
+                self.position = if self.position == 0 { len - 1 } else { self.position - 1 };
+                self.history[self.position] = newest;
+                self.history[self.position + len] = newest;
+                self.energy = (self.energy + (newest * newest) as f64 - (oldest * oldest) as f64).max(0.0);
+
+                if self.energy < REGULARIZATION as f64 {
+                    // Nothing has been played recently, so there's no echo to take out
+                    return sample;
+                }
+
+                let reference = &self.history[self.position..self.position + len];
+                let echo: f32 = self.weights.iter().zip(reference).map(|(w, r)| w * r).sum();
+                let error = sample - echo;
+
+                if sample.abs() > DOUBLE_TALK_RATIO * reference_peak {
+                    self.double_talk_remaining = self.double_talk_hold;
+                }
+                if self.double_talk_remaining > 0 {
+                    self.double_talk_remaining -= 1;
+                } else {
+                    let step = self.step_size * error / (self.energy as f32 + REGULARIZATION);
+                    for (weight, reference) in self.weights.iter_mut().zip(reference) {
+                        *weight += step * reference;
+                    }
+                }
+                error
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use crate::echo_canceller::EchoCanceller;
+
+    const SAMPLE_RATE: u32 = 16_000;
+
+    // Repeatable white noise between -amplitude and amplitude, standing in for speech
+    fn noise(len: usize, amplitude: f32, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                ((state >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    // What a mic 2.5ms from the speaker might hear, with a couple of reflections off the walls
+    fn room_echo(reference: &[f32]) -> Vec<f32> {
+        let taps = [(40, 0.5), (41, -0.2), (90, 0.15), (300, -0.08), (600, 0.04)];
+        (0..reference.len())
+            .map(|i| taps.iter().filter(|(delay, _)| i >= *delay).map(|(delay, gain)| gain * reference[i - delay]).sum())
+            .collect()
+    }
+
+    fn power(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32
+    }
+
+    // Runs a recorded mic/reference pair through in 100ms blocks, like the capture loop does
+    fn cancel(mic: &[f32], reference: &[f32]) -> Vec<f32> {
+        let mut canceller = EchoCanceller::new(SAMPLE_RATE, 64, 0.5);
+        mic.chunks(1600)
+            .zip(reference.chunks(1600))
+            .flat_map(|(mic, reference)| canceller.process(mic, reference))
+            .collect()
+    }
+
+    #[test]
+    fn test_cancels_echo() {
+        let reference = noise(SAMPLE_RATE as usize * 3, 0.5, 1);
+        let mic = room_echo(&reference);
+        let output = cancel(&mic, &reference);
+
+        // Echo return loss enhancement over the last second, once the filter has converged
+        let last_second = SAMPLE_RATE as usize * 2..;
+        let erle = 10.0 * (power(&mic[last_second.clone()]) / power(&output[last_second])).log10();
+        assert!(erle > 30.0, "ERLE was {} dB", erle);
+    }
+
+    #[test]
+    fn test_keeps_the_user_talking_over_playback() {
+        let len = SAMPLE_RATE as usize * 4;
+        let reference = noise(len, 0.5, 1);
+        let user: Vec<f32> = (0..len)
+            .map(|i| if i < len / 2 { 0.0 } else { 0.3 * (2.0 * PI * 300.0 * i as f32 / SAMPLE_RATE as f32).sin() })
+            .collect();
+        let mic: Vec<f32> = room_echo(&reference).iter().zip(&user).map(|(echo, user)| echo + user).collect();
+        let output = cancel(&mic, &reference);
+
+        // What's left is the user, with the echo mostly gone
+        let talking = len * 3 / 4..;
+        let residual: Vec<f32> = output[talking.clone()].iter().zip(&user[talking.clone()]).map(|(o, u)| o - u).collect();
+        let echo: Vec<f32> = mic[talking.clone()].iter().zip(&user[talking.clone()]).map(|(m, u)| m - u).collect();
+        assert!(power(&residual) < 0.1 * power(&echo), "{} vs {}", power(&residual), power(&echo));
+        assert!((power(&output[talking.clone()]) / power(&user[talking]) - 1.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn test_passes_mic_through_without_playback() {
+        let mut canceller = EchoCanceller::new(SAMPLE_RATE, 64, 0.5);
+        let mic = noise(4800, 0.1, 2);
+        assert_eq!(canceller.process(&mic, &[]), mic);
+    }
+}
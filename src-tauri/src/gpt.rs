@@ -1,47 +1,149 @@
 use anyhow::{Error, Result};
-use async_openai::Client;
-use async_openai::types::{ChatCompletionFunctionsArgs, ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs, Role};
+use async_openai::types::{ChatCompletionFunctions, ChatCompletionFunctionsArgs, ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs, FunctionCall, Role};
 use serde_json::json;
-use tauri::AppHandle;
-use crate::stores::get_from_store;
-
-pub async fn get_gpt_response(messages: Vec<ChatCompletionRequestMessage>) -> Result<ChatCompletionRequestMessage, Error> {
-    let client = Client::new();
+use futures::StreamExt;
+use tauri::async_runtime::Sender;
+use crate::chat_provider::{ChatProvider, ChatReply};
+use crate::language::find_language;
+use crate::text_to_speech::SpeechChunk;
+use crate::routine::{Routine, RoutineProgress};
 
+pub fn conversation_functions() -> Result<Vec<ChatCompletionFunctions>, Error> {
     let function = ChatCompletionFunctionsArgs::default()
         .name("leave_conversation")
         .description("The GPT AI can choose to call this function to leave the conversation whenever it appears finished, or if the user is unintelligible more than 3 times in a row.")
         .parameters(json!({"type": "object", "properties": {}}))
         .build()?;
 
-    let request = CreateChatCompletionRequestArgs::default()
-        .model("gpt-3.5-turbo")
-        .max_tokens(120_u16)
-        .messages(messages.clone())
-        .functions(vec![function])
+    let mark_step_complete = ChatCompletionFunctionsArgs::default()
+        .name("mark_step_complete")
+        .description("Call this function as soon as the user says they have finished a step of their routine.")
+        .parameters(json!({
+            "type": "object",
+            "properties": {
+                "step_id": {"type": "string", "description": "The id of the step, e.g. step-1"}
+            },
+            "required": ["step_id"]
+        }))
+        .build()?;
+
+    let skip_step = ChatCompletionFunctionsArgs::default()
+        .name("skip_step")
+        .description("Call this function when the user has a genuine reason they cannot do a step today.")
+        .parameters(json!({
+            "type": "object",
+            "properties": {
+                "step_id": {"type": "string", "description": "The id of the step, e.g. step-1"},
+                "reason": {"type": "string", "description": "Why the user is skipping the step"}
+            },
+            "required": ["step_id", "reason"]
+        }))
         .build()?;
 
-    let resp = client.chat().create(request).await?;
+    Ok(vec![function, mark_step_complete, skip_step])
+}
 
-    let resp_message = resp.choices.get(0).unwrap().message.clone();
+// Streams the reply, sending each finished sentence to TTS as soon as it is complete.
+// Routine function calls are returned as an assistant message with `function_call` set. Anything
+// said before a function call is ended as a turn of its own, so it still gets recorded
+pub async fn get_gpt_response(provider: &dyn ChatProvider, messages: Vec<ChatCompletionRequestMessage>, sentence_tx: &Sender<SpeechChunk>) -> Result<ChatCompletionRequestMessage, Error> {
+    let mut stream = provider.complete_stream(messages, conversation_functions()?).await?;
+    let mut segmenter = SentenceSegmenter::default();
+    let mut bot_string = String::new();
 
-    if let Some(function_call) = resp_message.function_call {
-        if function_call.name == "leave_conversation" {
-            return Ok(create_chat_completion_request_msg(
-                "Goodbye!".to_string(),
-                Role::System));
+    while let Some(reply) = stream.next().await {
+        match reply? {
+            ChatReply::FunctionCall { name, .. } if name == "leave_conversation" => {
+                end_spoken_turn(&mut segmenter, &bot_string, sentence_tx).await?;
+                return Ok(create_chat_completion_request_msg(
+                    "Goodbye!".to_string(),
+                    Role::System));
+            }
+            ChatReply::FunctionCall { name, arguments } => {
+                end_spoken_turn(&mut segmenter, &bot_string, sentence_tx).await?;
+                return Ok(ChatCompletionRequestMessageArgs::default()
+                    .role(Role::Assistant)
+                    .function_call(FunctionCall { name, arguments })
+                    .build()?);
+            }
+            ChatReply::Message(fragment) => {
+                bot_string.push_str(&fragment);
+                for sentence in segmenter.push(&fragment) {
+                    sentence_tx.send(SpeechChunk::Sentence(sentence)).await?;
+                }
+            }
         }
     }
 
-    let bot_string = resp_message.content.as_ref().unwrap().clone();
+    if let Some(sentence) = segmenter.finish() {
+        sentence_tx.send(SpeechChunk::Sentence(sentence)).await?;
+    }
 
     let new_bot_message = create_chat_completion_request_msg(
-        bot_string,
+        bot_string.trim().to_string(),
         Role::Assistant);
 
     return Ok(new_bot_message);
 }
 
+async fn end_spoken_turn(segmenter: &mut SentenceSegmenter, bot_string: &str, sentence_tx: &Sender<SpeechChunk>) -> Result<(), Error> {
+    if bot_string.trim().is_empty() {
+        return Ok(());
+    }
+    if let Some(sentence) = segmenter.finish() {
+        sentence_tx.send(SpeechChunk::Sentence(sentence)).await?;
+    }
+    sentence_tx.send(SpeechChunk::EndOfTurn).await?;
+    Ok(())
+}
+
+// Splits streamed text into sentences so each can be spoken while the rest is still generating
+#[derive(Default)]
+pub struct SentenceSegmenter {
+    buffer: String,
+}
+
+const ABBREVIATIONS: [&str; 8] = ["mr.", "mrs.", "ms.", "dr.", "st.", "e.g.", "i.e.", "etc."];
+
+impl SentenceSegmenter {
+    pub fn push(&mut self, fragment: &str) -> Vec<String> {
+        self.buffer.push_str(fragment);
+
+        let mut sentences = Vec::new();
+        let mut start = 0;
+        let mut chars = self.buffer.char_indices().peekable();
+
+        while let Some((i, c)) = chars.next() {
+            let is_terminator = matches!(c, '.' | '!' | '?' | '\n');
+            // A terminator only ends a sentence once we have seen the whitespace after it,
+            // otherwise "3.5" or a half-streamed "..." would be split
+            let followed_by_space = matches!(chars.peek(), Some((_, next)) if next.is_whitespace());
+            if !is_terminator || !(followed_by_space || c == '\n') {
+                continue;
+            }
+
+            let end = i + c.len_utf8();
+            let candidate = self.buffer[start..end].trim();
+            let last_word = candidate.rsplit(char::is_whitespace).next().unwrap_or("").to_lowercase();
+            if candidate.is_empty() || ABBREVIATIONS.contains(&last_word.as_str()) {
+                continue;
+            }
+
+            sentences.push(candidate.to_string());
+            start = end;
+        }
+
+        self.buffer = self.buffer[start..].to_string();
+        sentences
+    }
+
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = self.buffer.trim().to_string();
+        self.buffer.clear();
+        if rest.is_empty() { None } else { Some(rest) }
+    }
+}
+
 
 pub fn create_chat_completion_request_msg(content: String, role: Role) -> ChatCompletionRequestMessage {
     ChatCompletionRequestMessageArgs::default()
@@ -52,13 +154,130 @@ pub fn create_chat_completion_request_msg(content: String, role: Role) -> ChatCo
 }
 
 
-pub async fn messages_setup(handle: AppHandle) -> Vec<ChatCompletionRequestMessage> {
-    let system_message_content = "You are an AI personal routine trainer. You greet the user in the morning, then go through the user-provided morning routine checklist and ensure that the user completes each task on the list in order. Make sure to keep your tone positive, but it is vital that the user completes each task - do not allow them to 'skip' tasks. The user uses speech-to-text to communicate, so some of their messages may be incorrect - if some text seems out of place, please ignore it. If the users sentence makes no sense in the context, tell them you don't understand and ask them to repeat themselves. If you receive any text like [SILENCE] or [MUSIC] please respond with - I didn't catch that. The following message is the prompt the user provided - their morning checklist. Call the leave_conversation function when the user has completed their morning routine, or whenever the AI would normally say goodbye";
-    let system_message = create_chat_completion_request_msg(system_message_content.to_string(), Role::System);
+// language is the code of the language to speak, or None to answer in whatever language the user uses
+pub fn messages_setup(routine: &Routine, progress: &RoutineProgress, language: Option<&str>) -> Vec<ChatCompletionRequestMessage> {
+    let persona = if routine.persona.trim().is_empty() { "an AI personal routine trainer" } else { routine.persona.trim() };
+    let system_message_content = format!("You are {persona}. You greet the user at the start of their {name}, then go through the user-provided {name} checklist and ensure that the user completes each task on the list in order. Make sure to keep your tone positive, but it is vital that the user completes each task - only let them skip a task if they have a genuine reason, and call the skip_step function with that reason. Call the mark_step_complete function as soon as the user says they have finished a task. The user uses speech-to-text to communicate, so some of their messages may be incorrect - if some text seems out of place, please ignore it. If the users sentence makes no sense in the context, tell them you don't understand and ask them to repeat themselves. The following message is the user's {name} checklist, with the id and status of each step. Call the leave_conversation function when every step is completed or skipped, or whenever the AI would normally say goodbye", persona = persona, name = routine.name);
+    let language_instruction = match language.and_then(find_language) {
+        Some(language) => format!("Always speak to the user in {}, even though these instructions are in English.", language.name),
+        None => "Always reply in the language the user speaks to you in.".to_string(),
+    };
+    let system_message = create_chat_completion_request_msg(format!("{} {}", system_message_content, language_instruction), Role::System);
 
-    let user_prompt_content = get_from_store(handle, "userPrompt").unwrap_or("".to_string());
-    let user_prompt_message = create_chat_completion_request_msg(user_prompt_content, Role::System);
+    let checklist_message = create_chat_completion_request_msg(progress.describe(), Role::System);
 
-    return vec![system_message, user_prompt_message]
+    return vec![system_message, checklist_message]
 }
 
+// The result of a routine function call, sent back to GPT so it can continue the conversation
+pub fn create_function_result_msg(name: &str, result: String) -> Result<ChatCompletionRequestMessage, Error> {
+    Ok(ChatCompletionRequestMessageArgs::default()
+        .content(result)
+        .role(Role::Function)
+        .name(name)
+        .build()?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Result};
+    use async_openai::types::{ChatCompletionFunctions, ChatCompletionRequestMessage, Role};
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use crate::chat_provider::{ChatProvider, ChatReply, ChatStream, ScriptedProvider};
+    use crate::gpt::{create_chat_completion_request_msg, get_gpt_response, messages_setup, SentenceSegmenter};
+    use crate::routine::{Routine, RoutineProgress};
+    use crate::text_to_speech::SpeechChunk;
+
+    #[test]
+    fn test_conversation_with_scripted_provider() {
+        let provider = ScriptedProvider::new(vec![
+            ChatReply::Message("Good morning! Have you showered yet?".to_string()),
+            ChatReply::FunctionCall { name: "leave_conversation".to_string(), arguments: "{}".to_string() },
+        ]);
+        let (sentence_tx, mut sentence_rx) = tauri::async_runtime::channel(20);
+        let mut messages = vec![create_chat_completion_request_msg("1.Shower".to_string(), Role::System)];
+
+        let first = block_on(get_gpt_response(&provider, messages.clone(), &sentence_tx)).unwrap();
+        assert_eq!(first.role, Role::Assistant);
+        assert_eq!(first.content.as_deref(), Some("Good morning! Have you showered yet?"));
+        assert!(matches!(sentence_rx.try_recv(), Ok(SpeechChunk::Sentence(s)) if s == "Good morning!"));
+        assert!(matches!(sentence_rx.try_recv(), Ok(SpeechChunk::Sentence(s)) if s == "Have you showered yet?"));
+
+        messages.push(first);
+        messages.push(create_chat_completion_request_msg("Yes, all done".to_string(), Role::User));
+        let second = block_on(get_gpt_response(&provider, messages, &sentence_tx)).unwrap();
+        assert_eq!(second.role, Role::System);
+    }
+
+    #[test]
+    fn test_routine_function_call_is_returned() {
+        let provider = ScriptedProvider::new(vec![
+            ChatReply::FunctionCall { name: "mark_step_complete".to_string(), arguments: r#"{"step_id":"step-1"}"#.to_string() },
+        ]);
+        let (sentence_tx, _sentence_rx) = tauri::async_runtime::channel(20);
+
+        let message = block_on(get_gpt_response(&provider, vec![], &sentence_tx)).unwrap();
+
+        assert_eq!(message.role, Role::Assistant);
+        assert_eq!(message.function_call.unwrap().name, "mark_step_complete");
+    }
+
+    // Streams its replies in one go, like a model that talks and then calls a function
+    struct StreamingProvider(Vec<ChatReply>);
+
+    #[async_trait]
+    impl ChatProvider for StreamingProvider {
+        async fn complete(&self, _messages: Vec<ChatCompletionRequestMessage>, _functions: Vec<ChatCompletionFunctions>) -> Result<ChatReply> {
+            Err(anyhow!("streaming only"))
+        }
+
+        async fn complete_stream(&self, _messages: Vec<ChatCompletionRequestMessage>, _functions: Vec<ChatCompletionFunctions>) -> Result<ChatStream> {
+            Ok(futures::stream::iter(self.0.clone().into_iter().map(Ok)).boxed())
+        }
+    }
+
+    #[test]
+    fn test_text_before_function_call_ends_turn() {
+        let provider = StreamingProvider(vec![
+            ChatReply::Message("Nice work. Next".to_string()),
+            ChatReply::Message(" up, stretching".to_string()),
+            ChatReply::FunctionCall { name: "mark_step_complete".to_string(), arguments: r#"{"step_id":"step-1"}"#.to_string() },
+        ]);
+        let (sentence_tx, mut sentence_rx) = tauri::async_runtime::channel(20);
+
+        let message = block_on(get_gpt_response(&provider, vec![], &sentence_tx)).unwrap();
+
+        assert_eq!(message.function_call.unwrap().name, "mark_step_complete");
+        assert!(matches!(sentence_rx.try_recv(), Ok(SpeechChunk::Sentence(s)) if s == "Nice work."));
+        assert!(matches!(sentence_rx.try_recv(), Ok(SpeechChunk::Sentence(s)) if s == "Next up, stretching"));
+        assert!(matches!(sentence_rx.try_recv(), Ok(SpeechChunk::EndOfTurn)));
+        assert!(sentence_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_sentence_segmenter_streamed_fragments() {
+        let mut segmenter = SentenceSegmenter::default();
+
+        assert!(segmenter.push("Great").is_empty());
+        assert!(segmenter.push(" job, Dr.").is_empty());
+        assert_eq!(segmenter.push(" Smith. Next is 3.5 min").len(), 1);
+        assert_eq!(segmenter.push("utes of stretching! Ready? "), vec!["Next is 3.5 minutes of stretching!", "Ready?"]);
+        assert_eq!(segmenter.push("Go"), Vec::<String>::new());
+        assert_eq!(segmenter.finish(), Some("Go".to_string()));
+        assert_eq!(segmenter.finish(), None);
+    }
+
+    #[test]
+    fn test_messages_setup_follows_language() {
+        let routine = Routine::default();
+        let progress = RoutineProgress::new(&routine);
+
+        let spanish = messages_setup(&routine, &progress, Some("es"));
+        assert!(spanish[0].content.as_deref().unwrap().contains("in Spanish"));
+        let auto = messages_setup(&routine, &progress, None);
+        assert!(auto[0].content.as_deref().unwrap().contains("the language the user speaks"));
+    }
+}
@@ -0,0 +1,281 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SessionOutcome {
+    Completed,
+    // Sessions are saved as abandoned until the assistant ends them, so a crash or closed window is recorded as such
+    Abandoned,
+    // The routine was scheduled but the user never answered its reminders
+    Missed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionTurn {
+    pub timestamp: DateTime<Local>,
+    pub role: String,
+    pub text: String,
+    // STT confidence, only set for user turns
+    pub confidence: Option<f32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRecord {
+    pub id: String,
+    #[serde(default)]
+    pub routine_id: Option<String>,
+    pub started_at: DateTime<Local>,
+    pub ended_at: Option<DateTime<Local>>,
+    pub outcome: SessionOutcome,
+    pub turns: Vec<SessionTurn>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionSummary {
+    pub id: String,
+    pub routine_id: Option<String>,
+    pub started_at: DateTime<Local>,
+    pub ended_at: Option<DateTime<Local>>,
+    pub outcome: SessionOutcome,
+    pub turn_count: usize,
+}
+
+// Routine ids are whatever the user named them, so only letters and digits go into file names
+fn id_part(routine_id: &str) -> String {
+    routine_id.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect()
+}
+
+impl SessionRecord {
+    pub fn new(routine_id: &str) -> SessionRecord {
+        let started_at = Local::now();
+        SessionRecord {
+            // A stop and start, or the tray racing the scheduler, can start two in the same second
+            id: format!("{}-{}", started_at.format("%Y-%m-%dT%H-%M-%S-%3f"), id_part(routine_id)),
+            routine_id: Some(routine_id.to_string()),
+            started_at,
+            ended_at: None,
+            outcome: SessionOutcome::Abandoned,
+            turns: vec![],
+        }
+    }
+
+    pub fn missed(routine_id: &str, scheduled_for: DateTime<Local>) -> SessionRecord {
+        SessionRecord {
+            // Routines scheduled for the same time can both be missed
+            id: format!("{}-missed-{}", scheduled_for.format("%Y-%m-%dT%H-%M-%S"), id_part(routine_id)),
+            routine_id: Some(routine_id.to_string()),
+            started_at: scheduled_for,
+            ended_at: Some(Local::now()),
+            outcome: SessionOutcome::Missed,
+            turns: vec![],
+        }
+    }
+
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            id: self.id.clone(),
+            routine_id: self.routine_id.clone(),
+            started_at: self.started_at,
+            ended_at: self.ended_at,
+            outcome: self.outcome.clone(),
+            turn_count: self.turns.len(),
+        }
+    }
+}
+
+fn read_record(path: &Path) -> Result<SessionRecord> {
+    let contents = fs::read(path)?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+// One JSON file per session in <app data dir>/sessions
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: PathBuf) -> SessionStore {
+        SessionStore { dir }
+    }
+
+    pub fn from_handle(handle: &AppHandle) -> Result<SessionStore> {
+        let app_data_dir = handle.path_resolver().app_data_dir().ok_or(anyhow!("No app data directory"))?;
+        Ok(SessionStore::new(app_data_dir.join("sessions")))
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == 'T') {
+            return Err(anyhow!("Invalid session id: {}", id));
+        }
+        Ok(self.dir.join(format!("{}.json", id)))
+    }
+
+    pub fn save(&self, record: &SessionRecord) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(&record.id)?;
+        // Write then rename so a crash mid-write never leaves a truncated session
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(record)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<SessionRecord> {
+        read_record(&self.path(id)?)
+    }
+
+    fn load_all(&self) -> Result<Vec<SessionRecord>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut records = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            match read_record(&path) {
+                Ok(record) => records.push(record),
+                Err(e) => eprintln!("Skipping unreadable session {:?}: {}", path, e),
+            }
+        }
+        records.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+        Ok(records)
+    }
+
+    pub fn list(&self) -> Result<Vec<SessionSummary>> {
+        Ok(self.load_all()?.iter().map(|r| r.summary()).collect())
+    }
+
+    pub fn search(&self, query: &str) -> Result<Vec<SessionSummary>> {
+        let query = query.to_lowercase();
+        Ok(self.load_all()?
+            .iter()
+            .filter(|r| r.turns.iter().any(|t| t.text.to_lowercase().contains(&query)))
+            .map(|r| r.summary())
+            .collect())
+    }
+
+    pub fn delete(&self, id: &str) -> Result<()> {
+        fs::remove_file(self.path(id)?)?;
+        Ok(())
+    }
+}
+
+// The session currently running, saved to disk after every turn
+pub struct SessionRecorder {
+    store: SessionStore,
+    record: Mutex<SessionRecord>,
+}
+
+impl SessionRecorder {
+    pub fn new(store: SessionStore, routine_id: &str) -> SessionRecorder {
+        SessionRecorder { store, record: Mutex::new(SessionRecord::new(routine_id)) }
+    }
+
+    pub fn record_turn(&self, role: &str, text: &str, confidence: Option<f32>) {
+        let mut record = self.record.lock().unwrap();
+        record.turns.push(SessionTurn {
+            timestamp: Local::now(),
+            role: role.to_string(),
+            text: text.to_string(),
+            confidence,
+        });
+        if let Err(e) = self.store.save(&record) {
+            eprintln!("Failed to save session: {}", e);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.record.lock().unwrap().ended_at.is_some()
+    }
+
+    pub fn finish(&self, outcome: SessionOutcome) {
+        let mut record = self.record.lock().unwrap();
+        record.ended_at = Some(Local::now());
+        record.outcome = outcome;
+        if let Err(e) = self.store.save(&record) {
+            eprintln!("Failed to save session: {}", e);
+        }
+    }
+}
+
+#[tauri::command]
+pub fn list_sessions(handle: AppHandle) -> Result<Vec<SessionSummary>, String> {
+    SessionStore::from_handle(&handle).and_then(|s| s.list()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_session(handle: AppHandle, id: String) -> Result<SessionRecord, String> {
+    SessionStore::from_handle(&handle).and_then(|s| s.get(&id)).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn search_sessions(handle: AppHandle, query: String) -> Result<Vec<SessionSummary>, String> {
+    SessionStore::from_handle(&handle).and_then(|s| s.search(&query)).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn delete_session(handle: AppHandle, id: String) -> Result<(), String> {
+    SessionStore::from_handle(&handle).and_then(|s| s.delete(&id)).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use chrono::{Duration, Local};
+    use crate::history::{SessionOutcome, SessionRecord, SessionRecorder, SessionStore};
+
+    #[test]
+    fn test_session_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("sigma-sessions-{}", std::process::id()));
+        let store = SessionStore::new(dir.clone());
+
+        let recorder = SessionRecorder::new(SessionStore::new(dir.clone()), "morning");
+        recorder.record_turn("assistant", "Did you make your bed?", None);
+        recorder.record_turn("user", "Yes I made the bed", Some(0.92));
+        recorder.finish(SessionOutcome::Completed);
+
+        let mut abandoned = SessionRecord::new("evening");
+        abandoned.id = "2000-01-01T07-00-00".to_string();
+        abandoned.started_at = abandoned.started_at - Duration::days(1);
+        store.save(&abandoned).unwrap();
+
+        let sessions = store.list().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].outcome, SessionOutcome::Completed);
+        assert_eq!(sessions[0].turn_count, 2);
+
+        // Started together, but neither overwrites the other
+        store.save(&SessionRecord::new("weekend")).unwrap();
+        store.save(&SessionRecord::new("Evening & night")).unwrap();
+        assert_eq!(store.list().unwrap().len(), 4);
+        assert!(store.list().unwrap().iter().any(|s| s.id.ends_with("-Evening---night")));
+
+        let found = store.search("MADE THE BED").unwrap();
+        assert_eq!(found.len(), 1);
+        let session = store.get(&found[0].id).unwrap();
+        assert_eq!(session.turns[1].confidence, Some(0.92));
+
+        let scheduled_for = Local::now() - Duration::hours(2);
+        store.save(&SessionRecord::missed("morning", scheduled_for)).unwrap();
+        store.save(&SessionRecord::missed("weekend", scheduled_for)).unwrap();
+        assert_eq!(store.list().unwrap().iter().filter(|s| s.outcome == SessionOutcome::Missed).count(), 2);
+
+        store.delete("2000-01-01T07-00-00").unwrap();
+        assert_eq!(store.list().unwrap().len(), 5);
+        assert!(store.get("../settings").is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use tauri::AppHandle;
+use crate::stores::get_setting;
+
+pub struct Language {
+    // ISO 639-1, as used by whisper and the OS voices
+    pub code: &'static str,
+    pub name: &'static str,
+    pub greeting: &'static str,
+    // Said when a transcript is rejected, unless the user has set their own phrase
+    pub reprompt: &'static str,
+}
+
+pub const LANGUAGES: [Language; 16] = [
+    Language { code: "en", name: "English", greeting: "Good morning", reprompt: "Sorry, I didn't catch that." },
+    Language { code: "es", name: "Spanish", greeting: "Buenos días", reprompt: "Perdona, no te he entendido." },
+    Language { code: "fr", name: "French", greeting: "Bonjour", reprompt: "Désolé, je n'ai pas compris." },
+    Language { code: "de", name: "German", greeting: "Guten Morgen", reprompt: "Entschuldigung, das habe ich nicht verstanden." },
+    Language { code: "it", name: "Italian", greeting: "Buongiorno", reprompt: "Scusa, non ho capito." },
+    Language { code: "pt", name: "Portuguese", greeting: "Bom dia", reprompt: "Desculpe, não entendi." },
+    Language { code: "nl", name: "Dutch", greeting: "Goedemorgen", reprompt: "Sorry, dat verstond ik niet." },
+    Language { code: "sv", name: "Swedish", greeting: "God morgon", reprompt: "Förlåt, jag hörde inte vad du sa." },
+    Language { code: "pl", name: "Polish", greeting: "Dzień dobry", reprompt: "Przepraszam, nie zrozumiałem." },
+    Language { code: "ru", name: "Russian", greeting: "Доброе утро", reprompt: "Извините, я не расслышал." },
+    Language { code: "tr", name: "Turkish", greeting: "Günaydın", reprompt: "Üzgünüm, anlayamadım." },
+    Language { code: "ar", name: "Arabic", greeting: "صباح الخير", reprompt: "عذرًا، لم أفهم ذلك." },
+    Language { code: "hi", name: "Hindi", greeting: "सुप्रभात", reprompt: "माफ़ कीजिए, मैं समझ नहीं पाया।" },
+    Language { code: "ja", name: "Japanese", greeting: "おはようございます", reprompt: "すみません、聞き取れませんでした。" },
+    Language { code: "ko", name: "Korean", greeting: "좋은 아침입니다", reprompt: "죄송해요, 잘 못 들었어요." },
+    Language { code: "zh", name: "Chinese", greeting: "早上好", reprompt: "抱歉，我没听清楚。" },
+];
+
+// Accepts a code ("es") or an English name ("spanish"), which is what the OpenAI API returns
+pub fn find_language(code_or_name: &str) -> Option<&'static Language> {
+    let code_or_name = code_or_name.trim().to_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|language| language.code == code_or_name || language.name.to_lowercase() == code_or_name)
+}
+
+// The "language" setting, None when it's "auto" and should be detected from what the user says
+pub fn language_setting(handle: &AppHandle) -> Option<String> {
+    let setting = get_setting::<String>(handle.clone(), "language").unwrap_or("en".to_string());
+    if setting == "auto" {
+        return None;
+    }
+    match find_language(&setting) {
+        Some(language) => Some(language.code.to_string()),
+        None => {
+            eprintln!("Unsupported language {}, using English", setting);
+            Some("en".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::language::find_language;
+
+    #[test]
+    fn test_find_language() {
+        assert_eq!(find_language("es").unwrap().name, "Spanish");
+        assert_eq!(find_language("German").unwrap().code, "de");
+        assert_eq!(find_language(" english ").unwrap().code, "en");
+        assert!(find_language("klingon").is_none());
+    }
+}
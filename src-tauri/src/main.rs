@@ -1,30 +1,63 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod whisper;
+mod audio_input;
 mod text_to_speech;
 mod stores;
 mod audio_utils;
+mod echo_canceller;
+mod vad;
 mod voice_chat;
+mod voice_session;
 mod gpt;
+mod chat_provider;
+mod speech_to_text;
+mod history;
+mod language;
+mod model_manager;
+mod routine;
+mod scheduler;
+mod transcript_filter;
 
 use dotenv::dotenv;
 use std::{env, thread, time::Duration};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use tauri::{ActivationPolicy, AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayMenu, SystemTrayMenuItem, WindowBuilder, WindowUrl};
-use chrono::{Local, NaiveTime, Timelike};
+use tauri::api::notification::Notification;
+use tauri::{ActivationPolicy, AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayMenu, SystemTrayMenuItem, WindowBuilder, WindowEvent, WindowUrl};
+use chrono::{DateTime, Local, Utc};
 use tauri_plugin_autostart::MacosLauncher;
 use tauri_plugin_positioner::{Position, WindowExt};
-use crate::stores::get_from_store;
-use crate::voice_chat::start_voice_chat;
+use crate::history::{SessionRecord, SessionStore};
+use crate::routine::{find_routine, load_routines};
+use crate::scheduler::{ReminderConfig, ReminderEvent, Scheduler, SharedScheduler, SystemClock};
+use crate::stores::get_setting;
+use crate::voice_chat::{start_voice_chat, stop_voice_chat};
+use crate::voice_session::VoiceSessions;
+
+// Which routine the transcription window is for. Routines that come due while it's open for
+// another wait here, since there's only ever one voice chat
+#[derive(Default)]
+struct TranscriptionWindowState {
+    routine_id: Option<String>,
+    queued: VecDeque<String>,
+}
 
 fn main() {
     dotenv().ok();
 
     let record = CustomMenuItem::new("talk".to_string(), "Talk");
+    let snooze_5 = CustomMenuItem::new("snooze_5".to_string(), "Snooze 5 min");
+    let snooze_10 = CustomMenuItem::new("snooze_10".to_string(), "Snooze 10 min");
+    let snooze_30 = CustomMenuItem::new("snooze_30".to_string(), "Snooze 30 min");
     let settings = CustomMenuItem::new("settings".to_string(), "Settings");
     let quit = CustomMenuItem::new("quit".to_string(), "Quit");
     let tray_menu = SystemTrayMenu::new()
         .add_item(record)
+        .add_item(snooze_5)
+        .add_item(snooze_10)
+        .add_item(snooze_30)
         .add_item(settings)
         .add_native_item(SystemTrayMenuItem::Separator)
         .add_item(quit);
@@ -33,15 +66,21 @@ fn main() {
 
     let mut app = tauri::Builder::default()
         .setup(|app| {
+            // Routines missed while the computer was asleep still start if it wakes within 2 hours
+            let scheduler: SharedScheduler = Arc::new(Mutex::new(Scheduler::new(Arc::new(SystemClock), chrono::Duration::hours(2))));
+            app.manage(scheduler.clone());
+            app.manage(VoiceSessions::default());
+            app.manage(Mutex::new(TranscriptionWindowState::default()));
+
             // If we're in production, start waiting for the right time to start the voice chat.
             // In dev, start it right away.
             let is_production = env::var("IS_PRODUCTION").map_or(false, |v| v == "true");
             if is_production {
-                start_notification_loop(app.handle());
+                start_notification_loop(app.handle(), scheduler);
             } else {
                 let window_exists = app.handle().get_window("transcription_window").is_some();
                 if !window_exists {
-                    let _window = create_transcription_window(&app.handle());
+                    let _window = create_transcription_window(&app.handle(), None);
                 }
             }
 
@@ -50,14 +89,62 @@ fn main() {
         .plugin(tauri_plugin_positioner::init())
         .plugin(tauri_plugin_autostart::init(MacosLauncher::LaunchAgent, Some(vec!["--flag1", "--flag2"])))
         .plugin(tauri_plugin_store::Builder::default().build())
-        .invoke_handler(tauri::generate_handler![start_voice_chat])
+        .invoke_handler(tauri::generate_handler![
+            start_voice_chat,
+            stop_voice_chat,
+            audio_input::list_input_devices,
+            text_to_speech::list_voices,
+            scheduler::snooze_routine,
+            history::list_sessions,
+            history::get_session,
+            history::search_sessions,
+            history::delete_session,
+            model_manager::list_models,
+            model_manager::download_model,
+            model_manager::import_model
+        ])
+        .on_window_event(|event| {
+            // Closing the window ends the chat, rather than leaving the mic listening in the background
+            if event.window().label() == "transcription_window" {
+                if let WindowEvent::Destroyed = event.event() {
+                    let handle = event.window().app_handle();
+                    handle.state::<Mutex<TranscriptionWindowState>>().lock().unwrap().routine_id = None;
+                    tauri::async_runtime::spawn(async move {
+                        handle.state::<VoiceSessions>().stop().await;
+                        // Then on to whatever came due in the meantime
+                        let next = handle.state::<Mutex<TranscriptionWindowState>>().lock().unwrap().queued.pop_front();
+                        if let Some(routine_id) = next {
+                            create_transcription_window(&handle, Some(&routine_id));
+                        }
+                    });
+                }
+            }
+        })
         .system_tray(tray)
         .on_system_tray_event(|app_handle, event| {
             match event {
                 tauri::SystemTrayEvent::MenuItemClick { id, .. } => {
                     match id.as_str() {
                         "talk" => {
-                            create_transcription_window(app_handle);
+                            create_transcription_window(app_handle, None);
+                        }
+                        "snooze_5" | "snooze_10" | "snooze_30" => {
+                            let minutes = id.trim_start_matches("snooze_").parse().unwrap_or(10);
+                            match scheduler::snooze_routine(app_handle.state(), None, minutes) {
+                                Ok(snoozed) => {
+                                    // Only the window of a routine that was snoozed, not a chat the user started
+                                    let snoozed_window = {
+                                        let state = app_handle.state::<Mutex<TranscriptionWindowState>>();
+                                        let mut state = state.lock().unwrap();
+                                        state.queued.retain(|queued| !snoozed.contains(queued));
+                                        state.routine_id.as_ref().map_or(false, |routine_id| snoozed.contains(routine_id))
+                                    };
+                                    if snoozed_window {
+                                        close_transcription_window(app_handle);
+                                    }
+                                }
+                                Err(e) => eprintln!("Failed to snooze: {}", e),
+                            }
                         }
                         "settings" => {
                             let window_exists = app_handle.get_window("settings_window").is_some();
@@ -84,35 +171,117 @@ fn main() {
         });
 }
 
-fn start_notification_loop(handle: AppHandle) {
+// The scheduler is also polled at least this often, so settings changes, clock changes and
+// the computer waking up are noticed
+const MAX_SCHEDULER_SLEEP: Duration = Duration::from_secs(30);
+
+fn start_notification_loop(handle: AppHandle, scheduler: SharedScheduler) {
     thread::spawn(move || {
         loop {
-            sleep(Duration::from_secs(59));
+            let (due, reminders, next_wake) = {
+                let mut scheduler = scheduler.lock().unwrap();
+                scheduler.set_reminder_config(get_setting::<ReminderConfig>(handle.clone(), "reminders").unwrap_or_default());
+                let due = scheduler.poll(&load_routines(&handle));
+                (due, scheduler.poll_reminders(), scheduler.next_wake())
+            };
 
-            let time = get_from_store(handle.clone(), "time").unwrap_or("15:00".to_string());
-            let parsed_time = NaiveTime::parse_from_str(&time, "%H:%M");
-            let now = Local::now();
+            for due in due {
+                if due.late {
+                    println!("Catching up on {} scheduled for {}", due.routine_id, due.scheduled_for);
+                } else {
+                    println!("Chosen time reached! Starting {}", due.routine_id);
+                }
+                create_transcription_window(&handle, Some(&due.routine_id));
+            }
 
-            if now.hour() == parsed_time.unwrap().hour() && now.minute() == parsed_time.unwrap().minute() {
-                println!("Chosen time reached! Starting voice chat");
-                create_transcription_window(&handle);
+            for event in reminders {
+                match event {
+                    ReminderEvent::Remind { routine_id, reminder } => remind(&handle, &routine_id, reminder),
+                    ReminderEvent::GiveUp { routine_id, scheduled_for } => log_missed_session(&handle, &routine_id, scheduled_for),
+                }
             }
+
+            let wait = next_wake
+                .map(|next| (next - Utc::now()).to_std().unwrap_or_default())
+                .unwrap_or(MAX_SCHEDULER_SLEEP)
+                .min(MAX_SCHEDULER_SLEEP);
+            sleep(wait);
         }
     });
 }
 
-fn create_transcription_window(handle: &AppHandle) -> tauri::Window {
+fn remind(handle: &AppHandle, routine_id: &str, reminder: u32) {
+    let routine = find_routine(handle, Some(routine_id));
+    println!("Reminder {} for {}", reminder, routine.name);
+
+    let notification = Notification::new(&handle.config().tauri.bundle.identifier)
+        .title("Sigma")
+        .body(format!("Time for your {}", routine.name));
+    if let Err(e) = notification.show() {
+        eprintln!("Failed to show reminder: {}", e);
+    }
+
+    // None when another routine has the window, in which case this one opens once that ends
+    if let Some(window) = create_transcription_window(handle, Some(routine_id)) {
+        if let Err(e) = window.set_focus() {
+            eprintln!("Failed to focus transcription_window: {}", e);
+        }
+    }
+}
+
+fn log_missed_session(handle: &AppHandle, routine_id: &str, scheduled_for: DateTime<Utc>) {
+    println!("No answer for {}, giving up", routine_id);
+    let owns_window = {
+        let state = handle.state::<Mutex<TranscriptionWindowState>>();
+        let mut state = state.lock().unwrap();
+        state.queued.retain(|queued| queued != routine_id);
+        state.routine_id.as_deref() == Some(routine_id)
+    };
+    // A chat for another routine, or one the user started themselves, isn't this routine's to end.
+    // Its own chat hasn't heard from the user, or the scheduler would have stopped reminding
+    if owns_window {
+        close_transcription_window(handle);
+    }
 
-    let window_exists = handle.get_window("transcription_window").is_some();
+    let record = SessionRecord::missed(routine_id, scheduled_for.with_timezone(&Local));
+    if let Err(e) = SessionStore::from_handle(handle).and_then(|store| store.save(&record)) {
+        eprintln!("Failed to log missed session: {}", e);
+    }
+}
 
-    if window_exists {
-        return handle.get_window("transcription_window").unwrap();
+// Opens the window for the routine, or None if it's open for a different one and the routine was
+// queued instead. With no routine, any open window will do
+fn create_transcription_window(handle: &AppHandle, routine_id: Option<&str>) -> Option<tauri::Window> {
+    {
+        let state = handle.state::<Mutex<TranscriptionWindowState>>();
+        let mut state = state.lock().unwrap();
+        if let Some(window) = handle.get_window("transcription_window") {
+            match routine_id {
+                Some(routine_id) if state.routine_id.as_deref() != Some(routine_id) => {
+                    if !state.queued.iter().any(|queued| queued == routine_id) {
+                        println!("Starting {} once the current chat ends", routine_id);
+                        state.queued.push_back(routine_id.to_string());
+                    }
+                    return None;
+                }
+                _ => return Some(window),
+            }
+        }
+        // Released before building, which waits on the event loop and its Destroyed handler
+        state.routine_id = routine_id.map(|routine_id| routine_id.to_string());
     }
 
+    // The page passes the routine id on to start_voice_chat
+    let url = match routine_id {
+        // Routine ids are named by the user, so can contain anything
+        Some(routine_id) => format!("transcription?routine={}", urlencoding::encode(routine_id)),
+        None => "transcription".to_string(),
+    };
+
     let new_window = WindowBuilder::new(
         handle,
         "transcription_window",
-        WindowUrl::App("transcription".into())
+        WindowUrl::App(url.into())
     )
         .decorations(false)
         .transparent(true)
@@ -122,7 +291,15 @@ fn create_transcription_window(handle: &AppHandle) -> tauri::Window {
         .expect("Failed to create transcription_window");
 
     new_window.move_window(Position::TopCenter).expect("Failed to center window");
-    new_window
+    Some(new_window)
+}
+
+fn close_transcription_window(handle: &AppHandle) {
+    if let Some(window) = handle.get_window("transcription_window") {
+        if let Err(e) = window.close() {
+            eprintln!("Failed to close transcription_window: {}", e);
+        }
+    }
 }
 
 fn create_settings_window(handle: &AppHandle) -> tauri::Window {
@@ -0,0 +1,249 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tauri::{AppHandle, Manager};
+use crate::stores::get_setting;
+
+pub const DEFAULT_MODEL: &str = "base.en";
+const DOWNLOAD_URL: &str = "https://huggingface.co/ggerganov/whisper.cpp/resolve/main";
+
+pub struct WhisperModel {
+    pub name: &'static str,
+    pub english_only: bool,
+    pub size_mb: u64,
+    // SHA-1 of the ggml file, as published by whisper.cpp
+    pub sha1: &'static str,
+}
+
+pub const MODELS: [WhisperModel; 8] = [
+    WhisperModel { name: "tiny", english_only: false, size_mb: 75, sha1: "bd577a113a864445d4c299885e0cb97d4ba92b5f" },
+    WhisperModel { name: "tiny.en", english_only: true, size_mb: 75, sha1: "c78c86eb1a8faa21b369bcd33207cc90d64ae9df" },
+    WhisperModel { name: "base", english_only: false, size_mb: 142, sha1: "465707469ff3a37a2b9b8d8f89f2f99de7299dac" },
+    WhisperModel { name: "base.en", english_only: true, size_mb: 142, sha1: "137c40403d78fd54d454da0f9bd998f78703390c" },
+    WhisperModel { name: "small", english_only: false, size_mb: 466, sha1: "55356645c2b361a969dfd0ef2c5a50d530afd8d5" },
+    WhisperModel { name: "small.en", english_only: true, size_mb: 466, sha1: "db8a495a91d927739e50b3fc1cc4c6b8f6c2d022" },
+    WhisperModel { name: "medium", english_only: false, size_mb: 1500, sha1: "fd9727b6e1217c2f614f9b698455c4ffd82463b4" },
+    WhisperModel { name: "medium.en", english_only: true, size_mb: 1500, sha1: "8c30f0e44ce9560643ebd10bbe50cd20eafd3723" },
+];
+
+impl WhisperModel {
+    pub fn file_name(&self) -> String {
+        format!("ggml-{}.bin", self.name)
+    }
+
+    fn url(&self) -> String {
+        format!("{}/{}", DOWNLOAD_URL, self.file_name())
+    }
+}
+
+pub fn find_model(name: &str) -> Result<&'static WhisperModel> {
+    MODELS.iter().find(|model| model.name == name).ok_or(anyhow!("Unknown whisper model: {}", name))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelStatus {
+    pub name: String,
+    pub english_only: bool,
+    // The real size once installed, otherwise roughly what the download will be
+    pub size_bytes: u64,
+    pub installed: bool,
+    pub selected: bool,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DownloadProgress {
+    name: String,
+    downloaded: u64,
+    total: Option<u64>,
+}
+
+pub fn sha1_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Whisper models live in <app data dir>/models, named like whisper.cpp names them
+pub struct ModelManager {
+    dir: PathBuf,
+}
+
+impl ModelManager {
+    pub fn new(dir: PathBuf) -> ModelManager {
+        ModelManager { dir }
+    }
+
+    pub fn from_handle(handle: &AppHandle) -> Result<ModelManager> {
+        let app_data_dir = handle.path_resolver().app_data_dir().ok_or(anyhow!("No app data directory"))?;
+        Ok(ModelManager::new(app_data_dir.join("models")))
+    }
+
+    pub fn path(&self, model: &WhisperModel) -> PathBuf {
+        self.dir.join(model.file_name())
+    }
+
+    pub fn list(&self, selected: &str) -> Vec<ModelStatus> {
+        MODELS
+            .iter()
+            .map(|model| {
+                let size = fs::metadata(self.path(model)).ok().map(|metadata| metadata.len());
+                ModelStatus {
+                    name: model.name.to_string(),
+                    english_only: model.english_only,
+                    size_bytes: size.unwrap_or(model.size_mb * 1024 * 1024),
+                    installed: size.is_some(),
+                    selected: model.name == selected,
+                }
+            })
+            .collect()
+    }
+
+    // The path to load the model from, or an error the settings page can show
+    pub fn resolve(&self, name: &str) -> Result<PathBuf> {
+        let model = find_model(name)?;
+        let path = self.path(model);
+        if path.is_file() {
+            return Ok(path);
+        }
+
+        // Running from the repo, where the model used to be kept
+        let dev_path = Path::new("src").join(model.file_name());
+        if cfg!(debug_assertions) && dev_path.is_file() {
+            return Ok(dev_path);
+        }
+
+        Err(anyhow!("The {} speech recognition model isn't installed. Download or import it in Settings.", name))
+    }
+
+    pub fn verify(&self, model: &WhisperModel, path: &Path) -> Result<()> {
+        let checksum = sha1_file(path)?;
+        if checksum != model.sha1 {
+            return Err(anyhow!("{:?} is not the {} model (checksum {} doesn't match)", path, model.name, checksum));
+        }
+        Ok(())
+    }
+
+    // Copies a model the user already has into the models directory, checking it's the real thing first
+    pub fn import(&self, name: &str, source: &Path) -> Result<()> {
+        let model = find_model(name)?;
+        self.verify(model, source)?;
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(model);
+        let part_path = path.with_extension("bin.part");
+        fs::copy(source, &part_path)?;
+        fs::rename(part_path, path)?;
+        Ok(())
+    }
+
+    pub async fn download(&self, name: &str, mut on_progress: impl FnMut(u64, Option<u64>)) -> Result<()> {
+        let model = find_model(name)?;
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(model);
+        // Downloaded next to the real file so a cancelled download is never picked up as the model
+        let part_path = path.with_extension("bin.part");
+
+        let mut response = reqwest::get(model.url()).await?.error_for_status()?;
+        let total = response.content_length();
+        let mut file = fs::File::create(&part_path)?;
+        let mut hasher = Sha1::new();
+        let mut downloaded = 0;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk)?;
+            hasher.update(&chunk);
+            downloaded += chunk.len() as u64;
+            on_progress(downloaded, total);
+        }
+        drop(file);
+
+        let checksum = format!("{:x}", hasher.finalize());
+        if checksum != model.sha1 {
+            fs::remove_file(&part_path)?;
+            return Err(anyhow!("Downloaded {} model is corrupt (checksum {} doesn't match)", name, checksum));
+        }
+        fs::rename(part_path, path)?;
+        Ok(())
+    }
+}
+
+pub fn selected_model(handle: &AppHandle) -> String {
+    get_setting::<String>(handle.clone(), "whisperModel").unwrap_or(DEFAULT_MODEL.to_string())
+}
+
+#[tauri::command]
+pub fn list_models(handle: AppHandle) -> Result<Vec<ModelStatus>, String> {
+    let manager = ModelManager::from_handle(&handle).map_err(|e| e.to_string())?;
+    Ok(manager.list(&selected_model(&handle)))
+}
+
+#[tauri::command]
+pub async fn download_model(handle: AppHandle, name: String) -> Result<(), String> {
+    let manager = ModelManager::from_handle(&handle).map_err(|e| e.to_string())?;
+    manager
+        .download(&name, |downloaded, total| {
+            let progress = DownloadProgress { name: name.clone(), downloaded, total };
+            if let Err(e) = handle.emit_all("model_download_progress", progress) {
+                eprintln!("Failed to emit download progress: {}", e);
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn import_model(handle: AppHandle, name: String, path: String) -> Result<(), String> {
+    let manager = ModelManager::from_handle(&handle).map_err(|e| e.to_string())?;
+    // Hashing a large model takes a while, so keep it off the async runtime
+    tauri::async_runtime::spawn_blocking(move || manager.import(&name, Path::new(&path)))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::model_manager::{find_model, sha1_file, ModelManager};
+
+    #[test]
+    fn test_model_manager() {
+        let dir = std::env::temp_dir().join(format!("sigma-models-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manager = ModelManager::new(dir.join("models"));
+
+        let not_a_model = dir.join("abc.bin");
+        fs::write(&not_a_model, "abc").unwrap();
+        assert_eq!(sha1_file(&not_a_model).unwrap(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+
+        assert!(find_model("huge").is_err());
+        assert!(manager.resolve("huge").is_err());
+        assert!(manager.resolve("tiny").unwrap_err().to_string().contains("isn't installed"));
+        assert!(manager.import("tiny", &not_a_model).is_err());
+        assert!(!manager.path(find_model("tiny").unwrap()).exists());
+
+        // Stand in for a real download, which would have passed the checksum
+        fs::create_dir_all(dir.join("models")).unwrap();
+        fs::write(manager.path(find_model("tiny").unwrap()), "abc").unwrap();
+        let models = manager.list("tiny");
+        let tiny = models.iter().find(|m| m.name == "tiny").unwrap();
+        assert!(tiny.installed && tiny.selected);
+        assert_eq!(tiny.size_bytes, 3);
+        assert!(!models.iter().find(|m| m.name == "medium.en").unwrap().installed);
+        assert!(manager.resolve("tiny").is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::AppHandle;
+use crate::scheduler::Schedule;
+use crate::stores::get_setting;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Step {
+    pub id: String,
+    pub title: String,
+}
+
+// Stored as a list under the "routines" key of the settings store
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Routine {
+    pub id: String,
+    pub name: String,
+    // Who the assistant should be for this routine, e.g. "a drill sergeant". Empty for the default trainer
+    pub persona: String,
+    pub schedule: Schedule,
+    pub steps: Vec<Step>,
+}
+
+impl Default for Routine {
+    fn default() -> Self {
+        Routine {
+            id: "morning".to_string(),
+            name: "morning routine".to_string(),
+            persona: "".to_string(),
+            schedule: Schedule::default(),
+            steps: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StepStatus {
+    Pending,
+    Completed,
+    Skipped,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StepProgress {
+    pub step_id: String,
+    pub title: String,
+    pub status: StepStatus,
+    pub skip_reason: Option<String>,
+}
+
+// Sent to the frontend as the "routine_progress" event whenever a step changes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutineProgress {
+    pub steps: Vec<StepProgress>,
+}
+
+pub fn load_routines(handle: &AppHandle) -> Vec<Routine> {
+    if let Some(routines) = get_setting::<Vec<Routine>>(handle.clone(), "routines") {
+        return routines;
+    }
+
+    // Older versions stored a single routine, or only a free text checklist, and one daily time
+    let mut routine = get_setting::<Routine>(handle.clone(), "routine").unwrap_or_else(|| {
+        let user_prompt = get_setting::<String>(handle.clone(), "userPrompt").unwrap_or_default();
+        Routine { steps: parse_checklist(&user_prompt), ..Routine::default() }
+    });
+    if let Some(time) = get_setting::<String>(handle.clone(), "time") {
+        routine.schedule.time = time;
+    }
+    vec![routine]
+}
+
+// Picks the requested routine, falling back to the first one when there's no id (e.g. "Talk" from the tray)
+pub fn find_routine(handle: &AppHandle, routine_id: Option<&str>) -> Routine {
+    let routines = load_routines(handle);
+    routine_id
+        .and_then(|id| routines.iter().find(|r| r.id == id))
+        .or(routines.first())
+        .cloned()
+        .unwrap_or_default()
+}
+
+// Turns "1.Shower\n2.Brush Teeth" into steps, dropping the numbering
+pub fn parse_checklist(text: &str) -> Vec<Step> {
+    text
+        .lines()
+        .map(|line| line.trim().trim_start_matches(|c: char| c.is_ascii_digit()).trim_start_matches(|c| c == '.' || c == ')' || c == '-').trim())
+        .filter(|title| !title.is_empty())
+        .enumerate()
+        .map(|(i, title)| Step { id: format!("step-{}", i + 1), title: title.to_string() })
+        .collect()
+}
+
+impl RoutineProgress {
+    pub fn new(routine: &Routine) -> RoutineProgress {
+        let steps = routine.steps
+            .iter()
+            .map(|step| StepProgress {
+                step_id: step.id.clone(),
+                title: step.title.clone(),
+                status: StepStatus::Pending,
+                skip_reason: None,
+            })
+            .collect();
+
+        RoutineProgress { steps }
+    }
+
+    fn step_mut(&mut self, step_id: &str) -> Result<&mut StepProgress> {
+        self.steps
+            .iter_mut()
+            .find(|step| step.step_id == step_id)
+            .ok_or(anyhow!("No step with id {}", step_id))
+    }
+
+    pub fn mark_complete(&mut self, step_id: &str) -> Result<()> {
+        let step = self.step_mut(step_id)?;
+        step.status = StepStatus::Completed;
+        step.skip_reason = None;
+        Ok(())
+    }
+
+    pub fn skip(&mut self, step_id: &str, reason: &str) -> Result<()> {
+        let step = self.step_mut(step_id)?;
+        step.status = StepStatus::Skipped;
+        step.skip_reason = Some(reason.to_string());
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.steps.iter().all(|step| step.status != StepStatus::Pending)
+    }
+
+    // The checklist as GPT sees it, one step per line with its id and status
+    pub fn describe(&self) -> String {
+        self.steps
+            .iter()
+            .map(|step| {
+                let status = match step.status {
+                    StepStatus::Pending => "pending".to_string(),
+                    StepStatus::Completed => "completed".to_string(),
+                    StepStatus::Skipped => format!("skipped: {}", step.skip_reason.clone().unwrap_or_default()),
+                };
+                format!("- [{}] {} ({})", step.step_id, step.title, status)
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    // Applies a mark_step_complete/skip_step call from GPT and returns the result to send back to it
+    pub fn apply_function_call(&mut self, name: &str, arguments: &str) -> Result<String> {
+        let arguments: Value = serde_json::from_str(arguments).unwrap_or(Value::Null);
+        let step_id = arguments["step_id"].as_str().ok_or(anyhow!("Missing step_id"))?;
+
+        match name {
+            "mark_step_complete" => self.mark_complete(step_id)?,
+            "skip_step" => {
+                let reason = arguments["reason"].as_str().unwrap_or("no reason given");
+                self.skip(step_id, reason)?
+            }
+            other => return Err(anyhow!("Unknown function: {}", other)),
+        }
+
+        let remaining = self.steps.iter().filter(|step| step.status == StepStatus::Pending).count();
+        Ok(format!("Updated {}. {} step(s) remaining.\n{}", step_id, remaining, self.describe()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routine::{parse_checklist, Routine, RoutineProgress, StepStatus};
+
+    #[test]
+    fn test_parse_checklist() {
+        let steps = parse_checklist("1.Shower\n2) Brush Teeth\n\n- Make Bed\n");
+
+        let titles: Vec<&str> = steps.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(titles, vec!["Shower", "Brush Teeth", "Make Bed"]);
+        assert_eq!(steps[2].id, "step-3");
+    }
+
+    #[test]
+    fn test_routine_from_older_settings() {
+        // Version with only a checklist stored under "routine"
+        let routine: Routine = serde_json::from_str(r#"{"steps": [{"id": "step-1", "title": "Shower"}]}"#).unwrap();
+
+        assert_eq!(routine.id, "morning");
+        assert_eq!(routine.schedule.time, "15:00");
+        assert_eq!(routine.steps.len(), 1);
+    }
+
+    #[test]
+    fn test_apply_function_calls() {
+        let routine = Routine { steps: parse_checklist("Shower\nBrush Teeth"), ..Routine::default() };
+        let mut progress = RoutineProgress::new(&routine);
+
+        progress.apply_function_call("mark_step_complete", r#"{"step_id": "step-1"}"#).unwrap();
+        assert!(!progress.is_finished());
+        let result = progress.apply_function_call("skip_step", r#"{"step_id": "step-2", "reason": "out of toothpaste"}"#).unwrap();
+
+        assert!(result.contains("0 step(s) remaining"));
+        assert!(progress.is_finished());
+        assert_eq!(progress.steps[0].status, StepStatus::Completed);
+        assert_eq!(progress.steps[1].skip_reason.as_deref(), Some("out of toothpaste"));
+        assert!(progress.apply_function_call("mark_step_complete", r#"{"step_id": "step-9"}"#).is_err());
+    }
+}
@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, Local, LocalResult, NaiveDate, NaiveTime, TimeZone, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use crate::routine::Routine;
+
+// How far ahead to look for the next match. Long enough for a "29 Feb" cron to come round
+const MAX_SEARCH_DAYS: i64 = 366 * 5;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Schedule {
+    // Local time of day, "HH:MM"
+    pub time: String,
+    // Days the routine runs on, e.g. ["Mon", "Wed"]. Empty means every day
+    pub weekdays: Vec<Weekday>,
+    // A 5 field cron expression ("30 7 * * 1-5"), used instead of time and weekdays when set
+    pub cron: Option<String>,
+    // IANA name like "Europe/London". The system timezone when not set
+    pub timezone: Option<String>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            time: "15:00".to_string(),
+            weekdays: vec![],
+            cron: None,
+            timezone: None,
+        }
+    }
+}
+
+impl Schedule {
+    fn cron_expr(&self) -> Result<CronExpr> {
+        if let Some(cron) = &self.cron {
+            return cron.parse();
+        }
+
+        let time = NaiveTime::parse_from_str(&self.time, "%H:%M")
+            .map_err(|e| anyhow!("Invalid time {}: {}", self.time, e))?;
+        let days_of_week = if self.weekdays.is_empty() {
+            "*".to_string()
+        } else {
+            self.weekdays
+                .iter()
+                .map(|day| day.num_days_from_sunday().to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        format!("{} {} * * {}", time.minute(), time.hour(), days_of_week).parse()
+    }
+
+    // The first time strictly after `after` that the schedule fires, or None if it never does
+    pub fn next_after(&self, after: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+        let cron = self.cron_expr()?;
+        match &self.timezone {
+            Some(name) => {
+                let tz = Tz::from_str(name).map_err(|_| anyhow!("Unknown timezone: {}", name))?;
+                Ok(cron.next_after(&tz, after))
+            }
+            None => Ok(cron.next_after(&Local, after)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronExpr {
+    // One bit per allowed value
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+const MONTH_NAMES: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+fn parse_value(value: &str, min: u32, names: &[&str]) -> Result<u32> {
+    let lower = value.to_lowercase();
+    if let Some(i) = names.iter().position(|name| *name == lower) {
+        return Ok(i as u32 + min);
+    }
+    value.parse().map_err(|_| anyhow!("Invalid cron value: {}", value))
+}
+
+// Parses one field like "*", "1-5", "*/15", "0,30" or "mon-fri" into a bit set
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| anyhow!("Invalid cron step: {}", step))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(anyhow!("Invalid cron step: 0"));
+        }
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start, min, names)?, parse_value(end, min, names)?),
+                // "5/10" means every 10 starting at 5
+                None if part.contains('/') => (parse_value(range, min, names)?, max),
+                None => {
+                    let value = parse_value(range, min, names)?;
+                    (value, value)
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(anyhow!("Cron field {} out of range {}-{}", part, min, max));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+impl FromStr for CronExpr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(anyhow!("Cron expression needs 5 fields, got {}: {}", fields.len(), s));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7, &DAY_NAMES)?;
+        // 7 is also Sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(CronExpr {
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days_of_month: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, &MONTH_NAMES)?,
+            days_of_week,
+            any_day_of_month: fields[2] == "*",
+            any_day_of_week: fields[4] == "*",
+        })
+    }
+}
+
+impl CronExpr {
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day_of_month = self.days_of_month & (1 << date.day()) != 0;
+        let day_of_week = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        // Like cron, when both day fields are restricted either one matching is enough
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => day_of_week,
+            (false, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        }
+    }
+
+    pub fn next_after<T: TimeZone>(&self, tz: &T, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start_date = after.with_timezone(tz).date_naive();
+
+        for day in 0..MAX_SEARCH_DAYS {
+            let date = start_date + Duration::days(day);
+            if !self.matches_date(date) {
+                continue;
+            }
+
+            for hour in (0..24).filter(|h| self.hours & (1 << h) != 0) {
+                for minute in (0..60).filter(|m| self.minutes & (1 << m) != 0) {
+                    match resolve_local(tz, date, hour, minute) {
+                        Some(fire_at) if fire_at > after => return Some(fire_at),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+// Turns a wall clock time into an instant, coping with DST changes. A time that happens twice
+// fires the first time, and a time skipped by the clocks going forward fires once they have
+fn resolve_local<T: TimeZone>(tz: &T, date: NaiveDate, hour: u32, minute: u32) -> Option<DateTime<Utc>> {
+    let naive = date.and_hms_opt(hour, minute, 0)?;
+
+    for shift in 0..=180 {
+        match tz.from_local_datetime(&(naive + Duration::minutes(shift))) {
+            LocalResult::Single(dt) => return Some(dt.with_timezone(&Utc)),
+            LocalResult::Ambiguous(earliest, _) => return Some(earliest.with_timezone(&Utc)),
+            LocalResult::None => continue,
+        }
+    }
+
+    None
+}
+
+// Managed by the app so the tray, the windows and the voice chat can snooze or answer reminders
+pub type SharedScheduler = Arc<Mutex<Scheduler>>;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// Stored under the "reminders" key of the settings store
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ReminderConfig {
+    // How long to wait for the user to answer before reminding them again
+    pub interval_minutes: i64,
+    // Reminders to send before giving up and logging the session as missed
+    pub max_reminders: u32,
+}
+
+impl Default for ReminderConfig {
+    fn default() -> Self {
+        ReminderConfig { interval_minutes: 10, max_reminders: 3 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReminderEvent {
+    // Counts up from 1
+    Remind { routine_id: String, reminder: u32 },
+    GiveUp { routine_id: String, scheduled_for: DateTime<Utc> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DueRoutine {
+    pub routine_id: String,
+    pub scheduled_for: DateTime<Utc>,
+    // Fired after the scheduled time, e.g. because the computer was asleep
+    pub late: bool,
+}
+
+struct Entry {
+    schedule: Schedule,
+    next: Option<DateTime<Utc>>,
+}
+
+// A routine that has started but the user hasn't answered yet
+struct Reminder {
+    scheduled_for: DateTime<Utc>,
+    sent: u32,
+    next_at: DateTime<Utc>,
+}
+
+// Works out which routines are due each time it's polled. It compares instants rather than the
+// current hour and minute, so a poll that runs late or twice in a minute never skips or repeats
+pub struct Scheduler {
+    clock: Arc<dyn Clock>,
+    // Triggers missed by more than this (e.g. asleep all night) are dropped rather than fired late
+    catch_up: Duration,
+    entries: HashMap<String, Entry>,
+    snoozed: HashMap<String, DateTime<Utc>>,
+    reminder_config: ReminderConfig,
+    reminders: HashMap<String, Reminder>,
+}
+
+impl Scheduler {
+    pub fn new(clock: Arc<dyn Clock>, catch_up: Duration) -> Scheduler {
+        Scheduler {
+            clock,
+            catch_up,
+            entries: HashMap::new(),
+            snoozed: HashMap::new(),
+            reminder_config: ReminderConfig::default(),
+            reminders: HashMap::new(),
+        }
+    }
+
+    pub fn set_reminder_config(&mut self, config: ReminderConfig) {
+        self.reminder_config = config;
+    }
+
+    fn reminder_interval(&self) -> Duration {
+        Duration::minutes(self.reminder_config.interval_minutes.max(1))
+    }
+
+    fn next_fire(schedule: &Schedule, routine_id: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        schedule.next_after(after).unwrap_or_else(|e| {
+            eprintln!("Invalid schedule for routine {}: {}", routine_id, e);
+            None
+        })
+    }
+
+    // Returns the routines that should start now. Routines are passed in each time so edits to
+    // the settings are picked up without restarting
+    pub fn poll(&mut self, routines: &[Routine]) -> Vec<DueRoutine> {
+        let now = self.clock.now();
+        let mut due = vec![];
+
+        self.entries.retain(|id, _| routines.iter().any(|r| &r.id == id));
+        self.snoozed.retain(|id, _| routines.iter().any(|r| &r.id == id));
+        self.reminders.retain(|id, _| routines.iter().any(|r| &r.id == id));
+
+        for routine in routines {
+            let entry = self.entries.entry(routine.id.clone()).or_insert_with(|| Entry {
+                schedule: routine.schedule.clone(),
+                next: Self::next_fire(&routine.schedule, &routine.id, now),
+            });
+            if entry.schedule != routine.schedule {
+                entry.schedule = routine.schedule.clone();
+                entry.next = Self::next_fire(&routine.schedule, &routine.id, now);
+            }
+
+            let mut fired = None;
+            if let Some(snoozed_until) = self.snoozed.get(&routine.id).copied() {
+                if snoozed_until <= now {
+                    self.snoozed.remove(&routine.id);
+                    fired = Some(snoozed_until);
+                }
+            }
+
+            if let Some(next) = entry.next {
+                if next <= now {
+                    // Several missed occurrences only ever fire once
+                    entry.next = Self::next_fire(&entry.schedule, &routine.id, now);
+                    if now - next > self.catch_up {
+                        eprintln!("Missed routine {} scheduled for {}", routine.id, next);
+                    } else {
+                        fired = fired.or(Some(next));
+                    }
+                }
+            }
+
+            if let Some(scheduled_for) = fired {
+                self.reminders.insert(routine.id.clone(), Reminder {
+                    scheduled_for,
+                    sent: 0,
+                    next_at: now + self.reminder_interval(),
+                });
+                due.push(DueRoutine {
+                    routine_id: routine.id.clone(),
+                    scheduled_for,
+                    late: now - scheduled_for > Duration::minutes(1),
+                });
+            }
+        }
+
+        due
+    }
+
+    // Reminds again about routines the user hasn't answered, and gives up on them after enough reminders
+    pub fn poll_reminders(&mut self) -> Vec<ReminderEvent> {
+        let now = self.clock.now();
+        let interval = self.reminder_interval();
+        let max_reminders = self.reminder_config.max_reminders;
+        let mut events = vec![];
+
+        self.reminders.retain(|routine_id, reminder| {
+            if reminder.next_at > now {
+                return true;
+            }
+            if reminder.sent >= max_reminders {
+                events.push(ReminderEvent::GiveUp {
+                    routine_id: routine_id.clone(),
+                    scheduled_for: reminder.scheduled_for,
+                });
+                return false;
+            }
+            reminder.sent += 1;
+            reminder.next_at = now + interval;
+            events.push(ReminderEvent::Remind { routine_id: routine_id.clone(), reminder: reminder.sent });
+            true
+        });
+
+        events
+    }
+
+    // The user has answered, so stop reminding them
+    pub fn acknowledge(&mut self, routine_id: &str) {
+        self.reminders.remove(routine_id);
+    }
+
+    // Routines waiting on the user, for the tray's snooze items
+    pub fn active_reminders(&self) -> Vec<String> {
+        self.reminders.keys().cloned().collect()
+    }
+
+    // Snoozing counts as an answer, the reminders start over when the snooze is up
+    pub fn snooze(&mut self, routine_id: &str, duration: Duration) {
+        self.reminders.remove(routine_id);
+        self.snoozed.insert(routine_id.to_string(), self.clock.now() + duration);
+    }
+
+    // The earliest time anything could fire, so the caller knows how long it can sleep
+    pub fn next_wake(&self) -> Option<DateTime<Utc>> {
+        self.entries
+            .values()
+            .filter_map(|entry| entry.next)
+            .chain(self.snoozed.values().copied())
+            .chain(self.reminders.values().map(|reminder| reminder.next_at))
+            .min()
+    }
+}
+
+// Snoozes the given routine, or every routine waiting on the user when there's no id, returning
+// the ones it snoozed
+#[tauri::command]
+pub fn snooze_routine(scheduler: State<'_, SharedScheduler>, routine_id: Option<String>, minutes: i64) -> Result<Vec<String>, String> {
+    if minutes <= 0 {
+        return Err(format!("Can't snooze for {} minutes", minutes));
+    }
+
+    let mut scheduler = scheduler.lock().map_err(|e| e.to_string())?;
+    let routine_ids = match routine_id {
+        Some(routine_id) => vec![routine_id],
+        None => scheduler.active_reminders(),
+    };
+    for routine_id in &routine_ids {
+        println!("Snoozing {} for {} minutes", routine_id, minutes);
+        scheduler.snooze(routine_id, Duration::minutes(minutes));
+    }
+    Ok(routine_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use chrono::{DateTime, Duration, TimeZone, Utc, Weekday};
+    use chrono_tz::Tz;
+    use crate::routine::Routine;
+    use crate::scheduler::{Clock, CronExpr, DueRoutine, ReminderConfig, ReminderEvent, Schedule, Scheduler};
+
+    struct ManualClock {
+        now: Mutex<DateTime<Utc>>,
+    }
+
+    impl ManualClock {
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn routine(id: &str, schedule: Schedule) -> Routine {
+        Routine { id: id.to_string(), schedule, ..Routine::default() }
+    }
+
+    fn utc_schedule(time: &str) -> Schedule {
+        Schedule { time: time.to_string(), timezone: Some("UTC".to_string()), ..Schedule::default() }
+    }
+
+    fn scheduler_at(now: DateTime<Utc>) -> (Arc<ManualClock>, Scheduler) {
+        let clock = Arc::new(ManualClock { now: Mutex::new(now) });
+        let scheduler = Scheduler::new(clock.clone(), Duration::hours(2));
+        (clock, scheduler)
+    }
+
+    #[test]
+    fn test_cron_parsing() {
+        assert!("*/15 9-17 * * mon-fri".parse::<CronExpr>().is_ok());
+        assert!("0 7 1,15 jan,jul 0".parse::<CronExpr>().is_ok());
+        assert!("60 7 * * *".parse::<CronExpr>().is_err());
+        assert!("0 7 * *".parse::<CronExpr>().is_err());
+        assert!("*/0 7 * * *".parse::<CronExpr>().is_err());
+        assert!("0 7 * * 5-1".parse::<CronExpr>().is_err());
+    }
+
+    #[test]
+    fn test_daily_schedule() {
+        let schedule = utc_schedule("07:30");
+
+        assert_eq!(schedule.next_after(utc(2023, 3, 1, 6, 0)).unwrap(), Some(utc(2023, 3, 1, 7, 30)));
+        assert_eq!(schedule.next_after(utc(2023, 3, 1, 7, 30)).unwrap(), Some(utc(2023, 3, 2, 7, 30)));
+        assert!(utc_schedule("7.30").next_after(utc(2023, 3, 1, 6, 0)).is_err());
+    }
+
+    #[test]
+    fn test_weekday_schedule() {
+        // 2023-03-03 is a Friday
+        let schedule = Schedule { weekdays: vec![Weekday::Mon, Weekday::Wed], ..utc_schedule("07:00") };
+
+        assert_eq!(schedule.next_after(utc(2023, 3, 3, 12, 0)).unwrap(), Some(utc(2023, 3, 6, 7, 0)));
+        assert_eq!(schedule.next_after(utc(2023, 3, 6, 8, 0)).unwrap(), Some(utc(2023, 3, 8, 7, 0)));
+    }
+
+    #[test]
+    fn test_cron_schedule() {
+        let weekdays = Schedule { cron: Some("*/20 9 * * 1-5".to_string()), ..utc_schedule("") };
+        assert_eq!(weekdays.next_after(utc(2023, 3, 3, 9, 45)).unwrap(), Some(utc(2023, 3, 6, 9, 0)));
+
+        // Either day field matching is enough when both are set
+        let either = Schedule { cron: Some("0 8 13 * fri".to_string()), ..utc_schedule("") };
+        assert_eq!(either.next_after(utc(2023, 3, 4, 0, 0)).unwrap(), Some(utc(2023, 3, 10, 8, 0)));
+        assert_eq!(either.next_after(utc(2023, 3, 10, 9, 0)).unwrap(), Some(utc(2023, 3, 13, 8, 0)));
+
+        let leap_day = Schedule { cron: Some("0 8 29 2 *".to_string()), ..utc_schedule("") };
+        assert_eq!(leap_day.next_after(utc(2023, 3, 1, 0, 0)).unwrap(), Some(utc(2024, 2, 29, 8, 0)));
+
+        let never = Schedule { cron: Some("0 8 31 2 *".to_string()), ..utc_schedule("") };
+        assert_eq!(never.next_after(utc(2023, 3, 1, 0, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_timezone_and_dst() {
+        let new_york: Tz = "America/New_York".parse().unwrap();
+        let schedule = |time: &str| Schedule { time: time.to_string(), timezone: Some("America/New_York".to_string()), ..Schedule::default() };
+
+        // 07:00 stays 07:00 local across the March change, so the UTC time moves
+        let before = schedule("07:00").next_after(utc(2023, 3, 11, 0, 0)).unwrap().unwrap();
+        let after = schedule("07:00").next_after(before).unwrap().unwrap();
+        assert_eq!(before, new_york.with_ymd_and_hms(2023, 3, 11, 7, 0, 0).unwrap());
+        assert_eq!(after, new_york.with_ymd_and_hms(2023, 3, 12, 7, 0, 0).unwrap());
+        assert_eq!(after - before, Duration::hours(23));
+
+        // 02:30 doesn't exist on 12 March, it fires when the clocks reach 03:00
+        let skipped = schedule("02:30").next_after(utc(2023, 3, 12, 0, 0)).unwrap().unwrap();
+        assert_eq!(skipped, new_york.with_ymd_and_hms(2023, 3, 12, 3, 0, 0).unwrap());
+
+        // 01:30 happens twice on 5 November, it only fires the first time
+        let repeated = schedule("01:30").next_after(utc(2023, 11, 5, 0, 0)).unwrap().unwrap();
+        assert_eq!(repeated, utc(2023, 11, 5, 5, 30));
+        let following = schedule("01:30").next_after(repeated).unwrap().unwrap();
+        assert_eq!(following, new_york.with_ymd_and_hms(2023, 11, 6, 1, 30, 0).unwrap());
+
+        assert!(Schedule { timezone: Some("Mars/Olympus".to_string()), ..Schedule::default() }.next_after(utc(2023, 1, 1, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn test_scheduler_fires_once() {
+        let (clock, mut scheduler) = scheduler_at(utc(2023, 3, 1, 7, 0));
+        let routines = vec![routine("morning", utc_schedule("07:30"))];
+
+        assert!(scheduler.poll(&routines).is_empty());
+        assert_eq!(scheduler.next_wake(), Some(utc(2023, 3, 1, 7, 30)));
+
+        // Polls that land either side of the minute don't skip or repeat it
+        clock.advance(Duration::minutes(29) + Duration::seconds(59));
+        assert!(scheduler.poll(&routines).is_empty());
+        clock.advance(Duration::seconds(2));
+        assert_eq!(scheduler.poll(&routines), vec![DueRoutine {
+            routine_id: "morning".to_string(),
+            scheduled_for: utc(2023, 3, 1, 7, 30),
+            late: false,
+        }]);
+        clock.advance(Duration::seconds(30));
+        assert!(scheduler.poll(&routines).is_empty());
+
+        scheduler.acknowledge("morning");
+        assert_eq!(scheduler.next_wake(), Some(utc(2023, 3, 2, 7, 30)));
+    }
+
+    #[test]
+    fn test_scheduler_catches_up_after_sleep() {
+        let (clock, mut scheduler) = scheduler_at(utc(2023, 3, 1, 7, 0));
+        let routines = vec![routine("morning", utc_schedule("07:30")), routine("evening", utc_schedule("21:00"))];
+        scheduler.poll(&routines);
+
+        // Asleep from 07:00 to 08:15, the morning routine still runs
+        clock.advance(Duration::minutes(75));
+        let due = scheduler.poll(&routines);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].routine_id, "morning");
+        assert!(due[0].late);
+        scheduler.acknowledge("morning");
+
+        // Asleep for days, the evening routine is too late to catch up and only tomorrow's is kept
+        clock.advance(Duration::days(3));
+        assert!(scheduler.poll(&routines).is_empty());
+        assert_eq!(scheduler.next_wake(), Some(utc(2023, 3, 4, 21, 0)));
+    }
+
+    #[test]
+    fn test_scheduler_snooze() {
+        let (clock, mut scheduler) = scheduler_at(utc(2023, 3, 1, 7, 30));
+        let routines = vec![routine("morning", utc_schedule("07:30"))];
+        scheduler.poll(&routines);
+
+        scheduler.snooze("morning", Duration::minutes(10));
+        assert_eq!(scheduler.next_wake(), Some(utc(2023, 3, 1, 7, 40)));
+
+        clock.advance(Duration::minutes(9));
+        assert!(scheduler.poll(&routines).is_empty());
+        clock.advance(Duration::minutes(1));
+        let due = scheduler.poll(&routines);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].scheduled_for, utc(2023, 3, 1, 7, 40));
+        clock.advance(Duration::minutes(1));
+        assert!(scheduler.poll(&routines).is_empty());
+    }
+
+    #[test]
+    fn test_scheduler_picks_up_schedule_changes() {
+        let (clock, mut scheduler) = scheduler_at(utc(2023, 3, 1, 7, 0));
+        scheduler.poll(&[routine("morning", utc_schedule("07:30"))]);
+
+        let moved = vec![routine("morning", utc_schedule("07:10"))];
+        scheduler.poll(&moved);
+        assert_eq!(scheduler.next_wake(), Some(utc(2023, 3, 1, 7, 10)));
+
+        clock.advance(Duration::minutes(10));
+        assert_eq!(scheduler.poll(&moved).len(), 1);
+
+        scheduler.poll(&[]);
+        assert_eq!(scheduler.next_wake(), None);
+    }
+
+    #[test]
+    fn test_reminders_escalate_then_give_up() {
+        let (clock, mut scheduler) = scheduler_at(utc(2023, 3, 1, 7, 29));
+        scheduler.set_reminder_config(ReminderConfig { interval_minutes: 5, max_reminders: 2 });
+        let routines = vec![routine("morning", utc_schedule("07:30"))];
+        scheduler.poll(&routines);
+        clock.advance(Duration::minutes(1));
+        assert_eq!(scheduler.poll(&routines).len(), 1);
+        assert_eq!(scheduler.active_reminders(), vec!["morning".to_string()]);
+
+        clock.advance(Duration::minutes(4));
+        assert!(scheduler.poll_reminders().is_empty());
+        clock.advance(Duration::minutes(1));
+        assert_eq!(scheduler.poll_reminders(), vec![ReminderEvent::Remind { routine_id: "morning".to_string(), reminder: 1 }]);
+        clock.advance(Duration::minutes(5));
+        assert_eq!(scheduler.poll_reminders(), vec![ReminderEvent::Remind { routine_id: "morning".to_string(), reminder: 2 }]);
+        clock.advance(Duration::minutes(5));
+        assert_eq!(scheduler.poll_reminders(), vec![ReminderEvent::GiveUp {
+            routine_id: "morning".to_string(),
+            scheduled_for: utc(2023, 3, 1, 7, 30),
+        }]);
+
+        clock.advance(Duration::minutes(5));
+        assert!(scheduler.poll_reminders().is_empty());
+        assert!(scheduler.active_reminders().is_empty());
+    }
+
+    #[test]
+    fn test_answering_or_snoozing_stops_reminders() {
+        let (clock, mut scheduler) = scheduler_at(utc(2023, 3, 1, 7, 29));
+        let routines = vec![routine("morning", utc_schedule("07:30")), routine("evening", utc_schedule("07:30"))];
+        scheduler.poll(&routines);
+        clock.advance(Duration::minutes(1));
+        assert_eq!(scheduler.poll(&routines).len(), 2);
+
+        scheduler.acknowledge("morning");
+        scheduler.snooze("evening", Duration::minutes(30));
+        clock.advance(Duration::minutes(10));
+        assert!(scheduler.poll_reminders().is_empty());
+
+        // The snooze starts a new round of reminders
+        clock.advance(Duration::minutes(20));
+        assert_eq!(scheduler.poll(&routines).len(), 1);
+        clock.advance(Duration::minutes(10));
+        assert_eq!(scheduler.poll_reminders(), vec![ReminderEvent::Remind { routine_id: "evening".to_string(), reminder: 1 }]);
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::multipart::{Form, Part};
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+use whisper_rs::WhisperState;
+use crate::audio_utils::encode_wav;
+use crate::language::{find_language, language_setting};
+use crate::model_manager::{find_model, selected_model, ModelManager};
+use crate::stores::get_setting;
+use crate::whisper;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Transcript {
+    pub text: String,
+    pub segments: Vec<TranscriptSegment>,
+    // Average confidence over the segments, if the backend reports one
+    pub confidence: Option<f32>,
+    // Average chance that the segments are silence or noise rather than speech, if the backend reports one
+    pub no_speech_probability: Option<f32>,
+    // The language spoken, as a code like "es", if the backend knows it
+    pub language: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TranscriptSegment {
+    pub text: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub confidence: Option<f32>,
+    pub no_speech_probability: Option<f32>,
+    pub tokens: Vec<TranscriptToken>,
+}
+
+// A whisper token, or a word for backends that only report words
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TranscriptToken {
+    pub text: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub probability: Option<f32>,
+}
+
+fn average(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let values: Vec<f32> = values.collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f32>() / values.len() as f32)
+    }
+}
+
+impl TranscriptSegment {
+    // A segment covering the whole utterance, for backends that only return text
+    pub fn whole(text: String, samples: &[f32], sample_rate: u32, confidence: Option<f32>) -> TranscriptSegment {
+        TranscriptSegment {
+            text,
+            start_ms: 0,
+            end_ms: (samples.len() as i64 * 1000) / sample_rate.max(1) as i64,
+            confidence,
+            no_speech_probability: None,
+            tokens: vec![],
+        }
+    }
+
+    // For backends like whisper.cpp that don't report one. Text made up over silence or noise
+    // tends to be both unsure and spread thinly over the segment, since token times follow the
+    // audio's energy, while speech has confident tokens covering most of it
+    pub fn estimate_no_speech_probability(&self) -> Option<f32> {
+        let mean_probability = average(self.tokens.iter().filter_map(|t| t.probability))?;
+        let spoken_ms: i64 = self.tokens.iter().map(|t| (t.end_ms - t.start_ms).max(0)).sum();
+        let coverage = (spoken_ms as f32 / (self.end_ms - self.start_ms).max(1) as f32).clamp(0.0, 1.0);
+        Some(0.5 * (1.0 - mean_probability.clamp(0.0, 1.0)) + 0.5 * (1.0 - coverage))
+    }
+}
+
+impl Transcript {
+    pub fn from_segments(segments: Vec<TranscriptSegment>) -> Transcript {
+        let text = segments.iter().map(|s| s.text.as_str()).collect::<String>().trim().to_string();
+        let confidence = average(segments.iter().filter_map(|s| s.confidence));
+        let no_speech_probability = average(segments.iter().filter_map(|s| s.no_speech_probability));
+
+        Transcript { text, segments, confidence, no_speech_probability, language: None }
+    }
+}
+
+#[async_trait]
+pub trait SpeechToText: Send {
+    async fn transcribe(&mut self, samples: &[f32], sample_rate: u32) -> Result<Transcript>;
+
+    // True when each transcription is a request to a server, which costs time and money
+    fn is_remote(&self) -> bool {
+        false
+    }
+}
+
+// Selects the STT backend from the "sttBackend" setting, defaulting to the local whisper model
+pub async fn from_settings(handle: &AppHandle) -> Result<Box<dyn SpeechToText>> {
+    let backend = get_setting::<String>(handle.clone(), "sttBackend").unwrap_or("whisper".to_string());
+    let language = language_setting(handle);
+
+    match backend.as_str() {
+        "whisper" => {
+            let model_name = selected_model(handle);
+            let model_path = ModelManager::from_handle(handle)?.resolve(&model_name)?;
+            // English-only models can't detect or transcribe anything else
+            let language = if find_model(&model_name)?.english_only {
+                match language.as_deref() {
+                    None | Some("en") => Some("en".to_string()),
+                    Some(other) => return Err(anyhow!("The {} model only understands English. Pick a multilingual model in Settings to speak {}.", model_name, other)),
+                }
+            } else {
+                language
+            };
+            Ok(Box::new(WhisperSpeechToText::new(&model_path, language)?))
+        }
+        "openai" => {
+            let base_url = get_setting::<String>(handle.clone(), "sttBaseUrl")
+                .unwrap_or("https://api.openai.com/v1".to_string());
+            let model = get_setting::<String>(handle.clone(), "sttModel").unwrap_or("whisper-1".to_string());
+            let api_key = get_setting::<String>(handle.clone(), "sttApiKey");
+            Ok(Box::new(HttpSpeechToText::new(base_url, model, api_key, language)))
+        }
+        "fake" => {
+            let script = get_setting::<Vec<String>>(handle.clone(), "sttFakeScript").unwrap_or_default();
+            Ok(Box::new(FakeSpeechToText::new(script)))
+        }
+        other => Err(anyhow!("Unknown STT backend: {}", other)),
+    }
+}
+
+pub struct WhisperSpeechToText {
+    state: WhisperState<'static>,
+    // None to detect the language of each utterance
+    language: Option<String>,
+}
+
+impl WhisperSpeechToText {
+    pub fn new(model_path: &Path, language: Option<String>) -> Result<WhisperSpeechToText> {
+        let ctx = whisper::load_whisper_context(model_path)?;
+        let state = ctx.create_state().map_err(|e| anyhow!("Failed to create whisper state: {:?}", e))?;
+        Ok(WhisperSpeechToText { state, language })
+    }
+}
+
+#[async_trait]
+impl SpeechToText for WhisperSpeechToText {
+    async fn transcribe(&mut self, samples: &[f32], sample_rate: u32) -> Result<Transcript> {
+        let language = match &self.language {
+            Some(language) => language.clone(),
+            None => whisper::detect_language(samples, &mut self.state)?,
+        };
+        let segments = whisper::speech_to_text(samples, &mut self.state, &language)?;
+
+        Ok(Transcript { language: Some(language), ..Transcript::from_segments(segments) })
+    }
+}
+
+// Talks to any server implementing the OpenAI /audio/transcriptions endpoint
+pub struct HttpSpeechToText {
+    client: reqwest::Client,
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
+    language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VerboseTranscription {
+    text: String,
+    // Usually the English name of the language, e.g. "spanish"
+    language: Option<String>,
+    #[serde(default)]
+    segments: Vec<VerboseSegment>,
+    // Only there when word timestamps were asked for
+    #[serde(default)]
+    words: Vec<VerboseWord>,
+}
+
+#[derive(Deserialize)]
+struct VerboseSegment {
+    text: String,
+    start: f64,
+    end: f64,
+    avg_logprob: Option<f32>,
+    no_speech_prob: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct VerboseWord {
+    word: String,
+    start: f64,
+    end: f64,
+}
+
+impl HttpSpeechToText {
+    pub fn new(base_url: String, model: String, api_key: Option<String>, language: Option<String>) -> HttpSpeechToText {
+        HttpSpeechToText {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model,
+            api_key,
+            language,
+        }
+    }
+}
+
+#[async_trait]
+impl SpeechToText for HttpSpeechToText {
+    fn is_remote(&self) -> bool {
+        true
+    }
+
+    async fn transcribe(&mut self, samples: &[f32], sample_rate: u32) -> Result<Transcript> {
+        let wav = encode_wav(samples, sample_rate);
+        let mut form = Form::new()
+            .text("model", self.model.clone())
+            .text("response_format", "verbose_json")
+            .text("timestamp_granularities[]", "segment")
+            .text("timestamp_granularities[]", "word")
+            .part("file", Part::bytes(wav).file_name("audio.wav").mime_str("audio/wav")?);
+        if let Some(language) = &self.language {
+            form = form.text("language", language.clone());
+        }
+
+        let mut request = self.client
+            .post(format!("{}/audio/transcriptions", self.base_url))
+            .multipart(form);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response: VerboseTranscription = request.send().await?.error_for_status()?.json().await?;
+        let language = response.language
+            .as_deref()
+            .and_then(find_language)
+            .map(|language| language.code.to_string())
+            .or(self.language.clone());
+
+        if response.segments.is_empty() {
+            let transcript = Transcript::from_segments(vec![TranscriptSegment::whole(response.text, samples, sample_rate, None)]);
+            return Ok(Transcript { language, ..transcript });
+        }
+
+        let words: Vec<TranscriptToken> = response.words
+            .into_iter()
+            .map(|w| TranscriptToken {
+                text: w.word,
+                start_ms: (w.start * 1000.0) as i64,
+                end_ms: (w.end * 1000.0) as i64,
+                probability: None,
+            })
+            .collect();
+        let segments = response.segments
+            .into_iter()
+            .map(|s| {
+                let start_ms = (s.start * 1000.0) as i64;
+                let end_ms = (s.end * 1000.0) as i64;
+                TranscriptSegment {
+                    text: s.text,
+                    start_ms,
+                    end_ms,
+                    confidence: s.avg_logprob.map(|p| p.exp()),
+                    no_speech_probability: s.no_speech_prob,
+                    tokens: words.iter().filter(|w| w.start_ms >= start_ms && w.start_ms < end_ms).cloned().collect(),
+                }
+            })
+            .collect();
+
+        Ok(Transcript { language, ..Transcript::from_segments(segments) })
+    }
+}
+
+// Returns scripted transcripts in order, so the pipeline can run without a model
+pub struct FakeSpeechToText {
+    script: VecDeque<String>,
+}
+
+impl FakeSpeechToText {
+    pub fn new(script: Vec<String>) -> FakeSpeechToText {
+        FakeSpeechToText { script: script.into() }
+    }
+}
+
+#[async_trait]
+impl SpeechToText for FakeSpeechToText {
+    async fn transcribe(&mut self, samples: &[f32], sample_rate: u32) -> Result<Transcript> {
+        let text = self.script.pop_front().unwrap_or_default();
+
+        Ok(Transcript::from_segments(vec![TranscriptSegment::whole(text, samples, sample_rate, Some(1.0))]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use crate::speech_to_text::{FakeSpeechToText, SpeechToText, Transcript, TranscriptSegment, TranscriptToken};
+
+    #[test]
+    fn test_fake_returns_script_in_order() {
+        let mut stt = FakeSpeechToText::new(vec!["Hello".to_string(), "I made my bed".to_string()]);
+        let samples = vec![0.0; 16_000];
+
+        let first = block_on(stt.transcribe(&samples, 16_000)).unwrap();
+        let second = block_on(stt.transcribe(&samples, 16_000)).unwrap();
+        let third = block_on(stt.transcribe(&samples, 16_000)).unwrap();
+
+        assert_eq!(first.text, "Hello");
+        assert_eq!(first.segments[0].end_ms, 1000);
+        assert_eq!(second.text, "I made my bed");
+        assert_eq!(third.text, "");
+    }
+
+    #[test]
+    fn test_estimate_no_speech_probability() {
+        let segment = |end_ms, tokens: Vec<(i64, i64, f32)>| TranscriptSegment {
+            text: String::new(),
+            start_ms: 0,
+            end_ms,
+            confidence: None,
+            no_speech_probability: None,
+            tokens: tokens
+                .into_iter()
+                .map(|(start_ms, end_ms, p)| TranscriptToken { text: String::new(), start_ms, end_ms, probability: Some(p) })
+                .collect(),
+        };
+
+        // "I made my bed", confidently, over most of a second
+        let speech = segment(1000, vec![(0, 200, 0.9), (200, 450, 0.85), (450, 650, 0.95), (700, 950, 0.9)]);
+        assert!(speech.estimate_no_speech_probability().unwrap() < 0.2);
+        // "Thank you." made up over 10 seconds of silence
+        let hallucination = segment(10_000, vec![(0, 300, 0.4), (9_500, 10_000, 0.3)]);
+        assert!(hallucination.estimate_no_speech_probability().unwrap() > 0.6);
+        assert_eq!(segment(1000, vec![]).estimate_no_speech_probability(), None);
+    }
+
+    #[test]
+    fn test_transcript_from_segments() {
+        let segment = |text: &str, confidence, no_speech| TranscriptSegment {
+            text: text.to_string(),
+            start_ms: 0,
+            end_ms: 1000,
+            confidence,
+            no_speech_probability: no_speech,
+            tokens: vec![],
+        };
+        let transcript = Transcript::from_segments(vec![
+            segment(" I made", Some(0.9), Some(0.1)),
+            segment(" my bed.", Some(0.5), None),
+        ]);
+
+        assert_eq!(transcript.text, "I made my bed.");
+        assert!((transcript.confidence.unwrap() - 0.7).abs() < 1e-6);
+        assert_eq!(transcript.no_speech_probability, Some(0.1));
+        assert_eq!(Transcript::from_segments(vec![]).confidence, None);
+    }
+}
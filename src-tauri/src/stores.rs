@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use tauri::{AppHandle, Manager, Wry};
 use tauri_plugin_store::{StoreCollection, with_store};
@@ -22,6 +23,25 @@ pub fn get_from_store(handle: AppHandle, key: &str) -> Option<String>{
     retrieved
 }
 
+pub fn get_setting<T: DeserializeOwned>(handle: AppHandle, key: &str) -> Option<T> {
+    let stores = handle.state::<StoreCollection<Wry>>();
+    let path = PathBuf::from(".settings.dat");
+
+    let mut retrieved: Option<T> = None;
+
+    with_store(handle.clone(), stores, path, |store| {
+        if let Some(stored_value) = store.get(key) {
+            match serde_json::from_value(stored_value.clone()) {
+                Ok(value) => retrieved = Some(value),
+                Err(e) => eprintln!("Invalid value for setting {}: {}", key, e),
+            }
+        }
+        Ok(())
+    }).expect("Failed to interact with the store");
+
+    retrieved
+}
+
 pub fn set_in_store(handle: AppHandle, key: String, value: Value) {
     let stores = handle.state::<StoreCollection<Wry>>();
     let path = PathBuf::from(".settings.dat");
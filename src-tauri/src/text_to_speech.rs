@@ -1,50 +1,865 @@
-use anyhow::Result;
-use tts::*;
-
-#[cfg(target_os = "macos")]
-use cocoa_foundation::base::id;
-#[cfg(target_os = "macos")]
-use cocoa_foundation::foundation::NSDefaultRunLoopMode;
-#[cfg(target_os = "macos")]
-use cocoa_foundation::foundation::NSRunLoop;
-#[cfg(target_os = "macos")]
-use objc::class;
-#[cfg(target_os = "macos")]
-use objc::{msg_send, sel, sel_impl};
-use std::sync::mpsc;
+use std::env;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rodio::{Decoder, OutputStream, Sink, Source};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
 use tts::*;
 use tauri::AppHandle;
-use crate::stores::get_from_store;
+use crate::audio_utils::{encode_wav, PlaybackReference, ReferenceTap};
+use crate::language::find_language;
+use crate::stores::{get_from_store, get_setting};
+
+#[derive(Debug)]
+pub enum SpeechChunk {
+    Sentence(String),
+    EndOfTurn,
+    // The routine is done, once everything before it has been said
+    EndOfSession,
+}
+
+// Stored under the "voiceSettings" key of the settings store
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct VoiceSettings {
+    // An id from list_voices, or None for the backend's default voice
+    pub voice: Option<String>,
+    // Relative to the voice's normal speed and pitch, from 0.5 to 2.0
+    pub rate: f32,
+    pub pitch: f32,
+    // From 0.0 (silent) to 1.0 (the voice's normal volume)
+    pub volume: f32,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        VoiceSettings { voice: None, rate: 1.0, pitch: 1.0, volume: 1.0 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VoiceInfo {
+    pub id: String,
+    pub name: String,
+    pub language: Option<String>,
+}
+
+#[async_trait]
+pub trait TextToSpeech: Send + Sync {
+    // Resolves once the text has been spoken, or stop() cut it short
+    async fn speak(&self, text: &str) -> Result<()>;
+
+    fn stop(&self) -> Result<()>;
+
+    // Backends whose voice doesn't depend on the language ignore this
+    fn set_language(&self, _language: &str) -> Result<()> {
+        Ok(())
+    }
+
+    // Voices that can be stored in VoiceSettings::voice, empty when there's no choice
+    async fn voices(&self) -> Result<Vec<VoiceInfo>> {
+        Ok(vec![])
+    }
+
+    // Applies to every utterance after it. Settings a backend has no control over are ignored
+    fn set_voice_settings(&self, settings: &VoiceSettings) -> Result<()>;
+}
+
+pub type SharedTextToSpeech = Arc<dyn TextToSpeech>;
+
+// Selects the TTS backend from the "ttsBackend" setting, defaulting to the OS voices, and applies
+// the stored voice settings. Backends that play audio themselves copy it into the playback
+// reference for echo cancellation
+pub fn from_settings(handle: &AppHandle, playback: PlaybackReference) -> Result<SharedTextToSpeech> {
+    let tts = backend_from_settings(handle, playback)?;
+    let voice_settings = get_setting::<VoiceSettings>(handle.clone(), "voiceSettings").unwrap_or_default();
+    if let Err(e) = tts.set_voice_settings(&voice_settings) {
+        eprintln!("Failed to apply the voice settings: {}", e);
+    }
+    Ok(tts)
+}
+
+fn backend_from_settings(handle: &AppHandle, playback: PlaybackReference) -> Result<SharedTextToSpeech> {
+    let backend = get_setting::<String>(handle.clone(), "ttsBackend").unwrap_or("system".to_string());
+    let base_url = get_setting::<String>(handle.clone(), "ttsBaseUrl");
+    let api_key = get_setting::<String>(handle.clone(), "ttsApiKey");
+    let model = get_setting::<String>(handle.clone(), "ttsModel");
+
+    match backend.as_str() {
+        "system" => Ok(Arc::new(SystemTextToSpeech::new()?)),
+        "openai" => Ok(Arc::new(HttpTextToSpeech::new(
+            HttpTtsApi::OpenAi,
+            base_url.unwrap_or("https://api.openai.com/v1".to_string()),
+            api_key.or_else(|| env::var("OPENAI_API_KEY").ok()),
+            model.unwrap_or("tts-1".to_string()),
+            playback,
+        ))),
+        "elevenlabs" => Ok(Arc::new(HttpTextToSpeech::new(
+            HttpTtsApi::ElevenLabs,
+            base_url.unwrap_or("https://api.elevenlabs.io".to_string()),
+            api_key.or_else(|| env::var("ELEVENLABS_API_KEY").ok()),
+            // The monolingual model mispronounces anything but English
+            model.unwrap_or("eleven_multilingual_v2".to_string()),
+            playback,
+        ))),
+        "piper" => {
+            let program = get_setting::<String>(handle.clone(), "ttsPiperPath").unwrap_or("piper".to_string());
+            let model = get_setting::<String>(handle.clone(), "ttsPiperModel")
+                .ok_or(anyhow!("Choose a Piper voice model (.onnx) in Settings"))?;
+            Ok(Arc::new(PiperTextToSpeech::new(PathBuf::from(program), PathBuf::from(model), playback)))
+        }
+        "file" => {
+            let dir = match get_setting::<String>(handle.clone(), "ttsOutputDir") {
+                Some(dir) => PathBuf::from(dir),
+                None => handle.path_resolver().app_data_dir().ok_or(anyhow!("No app data directory"))?.join("tts"),
+            };
+            Ok(Arc::new(WavFileTextToSpeech::new(dir)?))
+        }
+        other => Err(anyhow!("Unknown TTS backend: {}", other)),
+    }
+}
+
+#[tauri::command]
+pub async fn list_voices(handle: AppHandle) -> Result<Vec<VoiceInfo>, String> {
+    let tts = backend_from_settings(&handle, PlaybackReference::default()).map_err(|e| e.to_string())?;
+    tts.voices().await.map_err(|e| e.to_string())
+}
+
+// Applies a 0.5-2.0 multiplier to a tts crate range's normal value, so 2.0 is twice the normal
+// speed wherever the platform allows it
+pub fn scale_to_range(multiplier: f32, min: f32, normal: f32, max: f32) -> f32 {
+    if normal > 0.0 {
+        return (normal * multiplier).clamp(min, max);
+    }
+    // Scales centred on zero, like speech-dispatcher's -100 to 100 rate, can't be multiplied, so
+    // 0.5 and 2.0 go to their ends
+    let position = multiplier.max(f32::MIN_POSITIVE).log2().clamp(-1.0, 1.0);
+    if position >= 0.0 {
+        normal + position * (max - normal)
+    } else {
+        normal + position * (normal - min)
+    }
+}
+
+// The voices that come with the OS, through the tts crate. The OS plays them, so the app never
+// has the audio and can't cancel their echo from the mic
+pub struct SystemTextToSpeech {
+    tts: Mutex<Tts>,
+}
+
+impl SystemTextToSpeech {
+    pub fn new() -> Result<SystemTextToSpeech> {
+        let tts = Tts::default().map_err(|e| anyhow!("Failed to start the system voice: {}", e))?;
+        Ok(SystemTextToSpeech { tts: Mutex::new(tts) })
+    }
+}
+
+#[async_trait]
+impl TextToSpeech for SystemTextToSpeech {
+    async fn speak(&self, text: &str) -> Result<()> {
+        let tts = self.tts.lock().unwrap().clone();
+        let text = text.to_string();
+        tauri::async_runtime::spawn_blocking(move || speak_string(&text, tts)).await?
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.tts.lock().unwrap().stop()?;
+        Ok(())
+    }
+
+    fn set_language(&self, language: &str) -> Result<()> {
+        select_voice_for_language(&mut self.tts.lock().unwrap(), language)
+    }
+
+    async fn voices(&self) -> Result<Vec<VoiceInfo>> {
+        let tts = self.tts.lock().unwrap();
+        if !tts.supported_features().voice {
+            return Ok(vec![]);
+        }
+        Ok(tts
+            .voices()?
+            .iter()
+            .map(|voice| VoiceInfo { id: voice.id(), name: voice.name(), language: Some(voice.language().to_string()) })
+            .collect())
+    }
+
+    fn set_voice_settings(&self, settings: &VoiceSettings) -> Result<()> {
+        let mut tts = self.tts.lock().unwrap();
+        let features = tts.supported_features();
+        if let Some(id) = &settings.voice {
+            if features.voice {
+                match tts.voices()?.into_iter().find(|voice| &voice.id() == id) {
+                    Some(voice) => {
+                        tts.set_voice(&voice)?;
+                    }
+                    None => eprintln!("Voice {} isn't installed, using the default", id),
+                }
+            }
+        }
+        if features.rate {
+            let rate = scale_to_range(settings.rate, tts.min_rate(), tts.normal_rate(), tts.max_rate());
+            tts.set_rate(rate)?;
+        }
+        if features.pitch {
+            let pitch = scale_to_range(settings.pitch, tts.min_pitch(), tts.normal_pitch(), tts.max_pitch());
+            tts.set_pitch(pitch)?;
+        }
+        if features.volume {
+            let volume = tts.min_volume() + settings.volume.clamp(0.0, 1.0) * (tts.normal_volume() - tts.min_volume());
+            tts.set_volume(volume)?;
+        }
+        Ok(())
+    }
+}
+
+// Plays synthesized audio so it can be stopped from another task, and so the echo canceller
+// can hear it
+#[derive(Clone)]
+struct AudioOutput {
+    sink: Arc<Mutex<Option<Arc<Sink>>>>,
+    // Bumped by stop(), so audio that was still being fetched isn't played afterwards
+    stops: Arc<AtomicUsize>,
+    volume: Arc<Mutex<f32>>,
+    playback: PlaybackReference,
+}
+
+impl AudioOutput {
+    fn new(playback: PlaybackReference) -> AudioOutput {
+        playback.mark_tapped();
+        AudioOutput {
+            sink: Arc::new(Mutex::new(None)),
+            stops: Arc::new(AtomicUsize::new(0)),
+            volume: Arc::new(Mutex::new(1.0)),
+            playback,
+        }
+    }
+
+    fn set_volume(&self, volume: f32) {
+        *self.volume.lock().unwrap() = volume.clamp(0.0, 1.0);
+    }
+
+    // Taken before fetching audio and passed to play()
+    fn generation(&self) -> usize {
+        self.stops.load(SeqCst)
+    }
+
+    async fn play(&self, audio: Bytes, generation: usize) -> Result<()> {
+        let output = self.clone();
+        tauri::async_runtime::spawn_blocking(move || output.play_blocking(audio, generation)).await?
+    }
+
+    fn play_blocking(&self, audio: Bytes, generation: usize) -> Result<()> {
+        // The output stream can't leave this thread, so it's opened for each utterance
+        let (_stream, stream_handle) = OutputStream::try_default()?;
+        let sink = Arc::new(Sink::try_new(&stream_handle)?);
+        let source = Decoder::new(Cursor::new(audio))?.convert_samples::<f32>().amplify(*self.volume.lock().unwrap());
+        // Tapped after the volume change, so the reference matches what comes out of the speakers
+        sink.append(ReferenceTap::new(source, self.playback.clone()));
+        {
+            let mut current = self.sink.lock().unwrap();
+            if self.stops.load(SeqCst) != generation {
+                return Ok(());
+            }
+            *current = Some(sink.clone());
+        }
+
+        sink.sleep_until_end();
+        *self.sink.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn stop(&self) {
+        let current = self.sink.lock().unwrap();
+        self.stops.fetch_add(1, SeqCst);
+        if let Some(sink) = current.as_ref() {
+            sink.stop();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpTtsApi {
+    // POST {base}/audio/speech, also served by many local TTS servers
+    OpenAi,
+    // POST {base}/v1/text-to-speech/{voice}
+    ElevenLabs,
+}
+
+// A neural voice from a TTS service. The base URL can point at a local server or a test stub
+pub struct HttpTextToSpeech {
+    client: reqwest::Client,
+    api: HttpTtsApi,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    voice: Mutex<String>,
+    // Only the OpenAI API takes a speed
+    speed: Mutex<f32>,
+    output: AudioOutput,
+}
+
+// OpenAI has no endpoint listing its voices
+const OPENAI_VOICES: [&str; 6] = ["alloy", "echo", "fable", "onyx", "nova", "shimmer"];
+
+impl HttpTtsApi {
+    fn default_voice(&self) -> &'static str {
+        match self {
+            HttpTtsApi::OpenAi => "alloy",
+            HttpTtsApi::ElevenLabs => "pMsXgVXv3BLzUgSXRplE",
+        }
+    }
+}
+
+impl HttpTextToSpeech {
+    pub fn new(api: HttpTtsApi, base_url: String, api_key: Option<String>, model: String, playback: PlaybackReference) -> HttpTextToSpeech {
+        HttpTextToSpeech {
+            client: reqwest::Client::new(),
+            api,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model,
+            voice: Mutex::new(api.default_voice().to_string()),
+            speed: Mutex::new(1.0),
+            output: AudioOutput::new(playback),
+        }
+    }
+
+    async fn synthesize(&self, text: &str) -> Result<Bytes> {
+        let voice = self.voice.lock().unwrap().clone();
+        let request = match self.api {
+            HttpTtsApi::OpenAi => {
+                let speed = *self.speed.lock().unwrap();
+                let request = self
+                    .client
+                    .post(format!("{}/audio/speech", self.base_url))
+                    .json(&json!({ "model": self.model, "input": text, "voice": voice, "speed": speed, "response_format": "wav" }));
+                match &self.api_key {
+                    Some(api_key) => request.bearer_auth(api_key),
+                    None => request,
+                }
+            }
+            HttpTtsApi::ElevenLabs => {
+                let request = self
+                    .client
+                    .post(format!("{}/v1/text-to-speech/{}", self.base_url, voice))
+                    .header("Accept", "audio/mpeg")
+                    .json(&json!({ "text": text, "model_id": self.model }));
+                match &self.api_key {
+                    Some(api_key) => request.header("xi-api-key", api_key),
+                    None => request,
+                }
+            }
+        };
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("TTS request failed with {}: {}", status, body));
+        }
+        Ok(response.bytes().await?)
+    }
+
+    async fn elevenlabs_voices(&self) -> Result<Vec<VoiceInfo>> {
+        let request = self.client.get(format!("{}/v1/voices", self.base_url));
+        let request = match &self.api_key {
+            Some(api_key) => request.header("xi-api-key", api_key),
+            None => request,
+        };
+        let response = request.send().await?.error_for_status()?.json::<Value>().await?;
+        let voices = response["voices"].as_array().ok_or(anyhow!("Unexpected voices response: {}", response))?;
+        Ok(voices
+            .iter()
+            .filter_map(|voice| {
+                Some(VoiceInfo {
+                    id: voice["voice_id"].as_str()?.to_string(),
+                    name: voice["name"].as_str()?.to_string(),
+                    language: voice["labels"]["language"].as_str().map(|language| language.to_string()),
+                })
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl TextToSpeech for HttpTextToSpeech {
+    async fn speak(&self, text: &str) -> Result<()> {
+        let generation = self.output.generation();
+        let audio = self.synthesize(text).await?;
+        self.output.play(audio, generation).await
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.output.stop();
+        Ok(())
+    }
+
+    async fn voices(&self) -> Result<Vec<VoiceInfo>> {
+        match self.api {
+            HttpTtsApi::OpenAi => Ok(OPENAI_VOICES
+                .iter()
+                .map(|voice| VoiceInfo { id: voice.to_string(), name: voice.to_string(), language: None })
+                .collect()),
+            HttpTtsApi::ElevenLabs => self.elevenlabs_voices().await,
+        }
+    }
+
+    fn set_voice_settings(&self, settings: &VoiceSettings) -> Result<()> {
+        *self.voice.lock().unwrap() = settings.voice.clone().unwrap_or(self.api.default_voice().to_string());
+        // OpenAI accepts 0.25 to 4.0
+        *self.speed.lock().unwrap() = settings.rate.clamp(0.5, 2.0);
+        self.output.set_volume(settings.volume);
+        Ok(())
+    }
+}
+
+// Numbers Piper's output files
+static PIPER_OUTPUTS: AtomicUsize = AtomicUsize::new(0);
+
+// A local voice run by the piper command line tool (https://github.com/rhasspy/piper) from an
+// ONNX voice model, so nothing leaves the computer
+pub struct PiperTextToSpeech {
+    program: PathBuf,
+    model: PathBuf,
+    rate: Mutex<f32>,
+    output: AudioOutput,
+}
+
+impl PiperTextToSpeech {
+    pub fn new(program: PathBuf, model: PathBuf, playback: PlaybackReference) -> PiperTextToSpeech {
+        PiperTextToSpeech { program, model, rate: Mutex::new(1.0), output: AudioOutput::new(playback) }
+    }
+
+    fn synthesize(program: PathBuf, model: PathBuf, rate: f32, text: String) -> Result<Bytes> {
+        // A file per utterance, since the next sentence can be synthesized while this one plays
+        let count = PIPER_OUTPUTS.fetch_add(1, SeqCst);
+        let path = env::temp_dir().join(format!("sigma-piper-{}-{}.wav", std::process::id(), count));
+        let audio = PiperTextToSpeech::run(&program, &model, rate, &text, &path);
+        // Piper may have written some of it before failing
+        if path.exists() {
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!("Failed to remove {:?}: {}", path, e);
+            }
+        }
+        audio
+    }
+
+    fn run(program: &Path, model: &Path, rate: f32, text: &str, path: &Path) -> Result<Bytes> {
+        let mut child = Command::new(program)
+            .arg("--model")
+            .arg(model)
+            // Piper stretches phonemes by this, so it's the inverse of the rate
+            .arg("--length_scale")
+            .arg((1.0 / rate).to_string())
+            .arg("--output_file")
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("Failed to run {:?}, is Piper installed? {}", program, e))?;
+        let mut stdin = child.stdin.take().ok_or(anyhow!("Failed to open Piper's stdin"))?;
+        stdin.write_all(text.as_bytes())?;
+        drop(stdin);
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!("Piper failed: {}", String::from_utf8_lossy(&output.stderr)));
+        }
+        Ok(Bytes::from(fs::read(path)?))
+    }
+}
+
+#[async_trait]
+impl TextToSpeech for PiperTextToSpeech {
+    async fn speak(&self, text: &str) -> Result<()> {
+        let generation = self.output.generation();
+        let (program, model, text) = (self.program.clone(), self.model.clone(), text.to_string());
+        let rate = *self.rate.lock().unwrap();
+        let audio = tauri::async_runtime::spawn_blocking(move || PiperTextToSpeech::synthesize(program, model, rate, text)).await??;
+        self.output.play(audio, generation).await
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.output.stop();
+        Ok(())
+    }
+
+    // The voice is the model chosen in settings
+    fn set_voice_settings(&self, settings: &VoiceSettings) -> Result<()> {
+        *self.rate.lock().unwrap() = settings.rate.clamp(0.5, 2.0);
+        self.output.set_volume(settings.volume);
+        Ok(())
+    }
+}
 
+const FILE_SAMPLE_RATE: u32 = 16_000;
+// Roughly how long a word takes to say, for the length of the silence written in its place
+const FILE_SECONDS_PER_WORD: f32 = 0.3;
+
+// Writes each utterance to utterance-001.wav, utterance-002.wav, ... with its text alongside in a
+// .txt file, instead of speaking. The audio is silence of about the right length, so the rest of
+// the app can be run and tested without a voice
+pub struct WavFileTextToSpeech {
+    dir: PathBuf,
+    spoken: Mutex<Vec<String>>,
+    rate: Mutex<f32>,
+}
+
+impl WavFileTextToSpeech {
+    pub fn new(dir: PathBuf) -> Result<WavFileTextToSpeech> {
+        fs::create_dir_all(&dir)?;
+        Ok(WavFileTextToSpeech { dir, spoken: Mutex::new(vec![]), rate: Mutex::new(1.0) })
+    }
+
+    pub fn spoken(&self) -> Vec<String> {
+        self.spoken.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl TextToSpeech for WavFileTextToSpeech {
+    async fn speak(&self, text: &str) -> Result<()> {
+        let mut spoken = self.spoken.lock().unwrap();
+        let name = format!("utterance-{:03}", spoken.len() + 1);
+        let words = text.split_whitespace().count();
+        let seconds = words as f32 * FILE_SECONDS_PER_WORD / *self.rate.lock().unwrap();
+        let samples = vec![0.0; (seconds * FILE_SAMPLE_RATE as f32) as usize];
+        fs::write(self.dir.join(format!("{}.wav", name)), encode_wav(&samples, FILE_SAMPLE_RATE))?;
+        fs::write(self.dir.join(format!("{}.txt", name)), text)?;
+        spoken.push(text.to_string());
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_voice_settings(&self, settings: &VoiceSettings) -> Result<()> {
+        *self.rate.lock().unwrap() = settings.rate.clamp(0.5, 2.0);
+        Ok(())
+    }
+}
+
+// How long an utterance may take before speak_string gives up on it, generous enough for the
+// slowest rate setting
+const SPEECH_TIMEOUT_BASE: Duration = Duration::from_secs(5);
+const SPEECH_TIMEOUT_PER_WORD: Duration = Duration::from_secs(1);
+const SPEECH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// is_speaking can still be false just after speak() while the engine starts up
+const SPEECH_START_GRACE: Duration = Duration::from_millis(500);
+// For engines that can't say when they're done, the pause before the next utterance
+const SPEECH_ESTIMATE_PER_WORD: Duration = Duration::from_millis(400);
+
+// Speaks the text and blocks until the engine is done with it or it's stopped. Engines report the
+// end through utterance callbacks where they support them, otherwise through is_speaking, and
+// failing both the wait is an estimate from the length of the text
 pub fn speak_string(text: &str, mut tts: Tts) -> Result<()> {
-    let (tx, rx) = mpsc::channel();
+    let features = tts.supported_features();
+    let words = text.split_whitespace().count() as u32;
 
-    tts.on_utterance_end(Some(Box::new(move |_| {
-        tx.send(()).unwrap();
-    })))?;
+    let done = if features.utterance_callbacks {
+        let (tx, rx) = mpsc::channel();
+        let stop_tx = tx.clone();
+        // A closed channel means speak_string already returned, so send errors are ignored
+        tts.on_utterance_end(Some(Box::new(move |_| {
+            let _ = tx.send(());
+        })))?;
+        // Fired instead of utterance_end when the user interrupts and tts.stop() is called
+        tts.on_utterance_stop(Some(Box::new(move |_| {
+            let _ = stop_tx.send(());
+        })))?;
+        Some(rx)
+    } else {
+        None
+    };
 
-    // tts.speak accepts only &str
     tts.speak(text, false)?;
-    // TODO: Try commenting this out to see if I need it - tauri might include NSRunLoop
-    #[cfg(target_os = "macos")]
-    {
-        let run_loop: id = unsafe { NSRunLoop::currentRunLoop() };
-        unsafe {
-            let date: id = msg_send![class!(NSDate), distantFuture];
-            let _: () = msg_send![run_loop, runMode:NSDefaultRunLoopMode beforeDate:date];
+
+    if done.is_none() && !features.is_speaking {
+        std::thread::sleep(SPEECH_ESTIMATE_PER_WORD * words);
+        return Ok(());
+    }
+
+    let timeout = SPEECH_TIMEOUT_BASE + SPEECH_TIMEOUT_PER_WORD * words;
+    let checker = tts.clone();
+    let is_speaking = move || -> Result<bool> { Ok(checker.is_speaking()?) };
+    let is_speaking: Option<&dyn Fn() -> Result<bool>> = if features.is_speaking { Some(&is_speaking) } else { None };
+    let result = wait_for_speech(done.as_ref(), is_speaking, timeout);
+    if result.is_err() {
+        // Don't let a stuck utterance talk over the next one
+        if let Err(e) = tts.stop() {
+            eprintln!("Failed to stop speech after a timeout: {}", e);
         }
     }
-    rx.recv().unwrap();
+    result
+}
+
+// Waits for an utterance callback, or for is_speaking to go false once the grace period is over.
+// Both are checked, since some engines claim callbacks but never fire them
+fn wait_for_speech(done: Option<&Receiver<()>>, is_speaking: Option<&dyn Fn() -> Result<bool>>, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        match done {
+            Some(done) => match done.recv_timeout(SPEECH_POLL_INTERVAL) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(anyhow!("The speech engine dropped its callbacks")),
+            },
+            None => std::thread::sleep(SPEECH_POLL_INTERVAL),
+        }
+
+        if let Some(is_speaking) = is_speaking {
+            if start.elapsed() >= SPEECH_START_GRACE && !is_speaking()? {
+                return Ok(());
+            }
+        }
+        if start.elapsed() >= timeout {
+            return Err(anyhow!("Speech didn't finish within {} seconds", timeout.as_secs()));
+        }
+    }
+}
+
+// Switches to an installed voice for the language, keeping the current voice if it already speaks
+// the language (so a voice chosen in settings sticks) or if there isn't one
+pub fn select_voice_for_language(tts: &mut Tts, language: &str) -> Result<()> {
+    if !tts.supported_features().voice {
+        return Ok(());
+    }
+    if tts.voice()?.map_or(false, |voice| voice.language().primary_language() == language) {
+        return Ok(());
+    }
+    let voices = tts.voices()?;
+    match voices.iter().find(|voice| voice.language().primary_language() == language) {
+        Some(voice) => tts.set_voice(voice)?,
+        None => eprintln!("No {} voice installed, using the current voice", language),
+    }
     Ok(())
 }
 
-pub async fn initial_speech(handle: AppHandle, tts: Tts) {
+pub async fn initial_speech(handle: AppHandle, tts: SharedTextToSpeech, language: Option<String>) {
     println!("Starting initial_speech");
+    let greeting = language.as_deref().and_then(find_language).map_or("Good morning", |language| language.greeting);
     let user_first_name = get_from_store(handle, "userFirstName");
     let initial_speech = match user_first_name {
-        Some(s) => format!("Good morning {}!", s),
-        None => "Good morning!".to_string(),
+        Some(s) => format!("{} {}!", greeting, s),
+        None => format!("{}!", greeting),
     };
-    speak_string(&initial_speech, tts);
+    if let Err(e) = tts.speak(&initial_speech).await {
+        eprintln!("Failed to speak the greeting: {}", e);
+    }
     println!("Finished initial_speech");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread::{self, JoinHandle};
+    use futures::executor::block_on;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+    use anyhow::Result;
+    use crate::audio_utils::{encode_wav, PlaybackReference};
+    use crate::text_to_speech::{scale_to_range, wait_for_speech, HttpTextToSpeech, HttpTtsApi, PiperTextToSpeech, TextToSpeech, VoiceSettings, WavFileTextToSpeech};
+
+    #[test]
+    fn test_wav_file_text_to_speech() {
+        let dir = std::env::temp_dir().join(format!("sigma-tts-{}", std::process::id()));
+        let tts = WavFileTextToSpeech::new(dir.clone()).unwrap();
+
+        block_on(tts.speak("Good morning!")).unwrap();
+        block_on(tts.speak("Have you made your bed?")).unwrap();
+        assert_eq!(tts.spoken(), vec!["Good morning!", "Have you made your bed?"]);
+
+        assert_eq!(fs::read_to_string(dir.join("utterance-002.txt")).unwrap(), "Have you made your bed?");
+        // 44 byte header, then 0.3s of 16-bit samples at 16 kHz for each of the two words
+        assert_eq!(fs::metadata(dir.join("utterance-001.wav")).unwrap().len(), 44 + 2 * 9600);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_wav_file_rate() {
+        let dir = std::env::temp_dir().join(format!("sigma-tts-rate-{}", std::process::id()));
+        let tts = WavFileTextToSpeech::new(dir.clone()).unwrap();
+
+        tts.set_voice_settings(&VoiceSettings { rate: 2.0, ..VoiceSettings::default() }).unwrap();
+        block_on(tts.speak("Twice as fast")).unwrap();
+        // Three words at 0.15s each
+        assert_eq!(fs::metadata(dir.join("utterance-001.wav")).unwrap().len(), 44 + 2 * 7200);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Answers one request with the given status and body, and hands back the request it got
+    fn stub_server(status: &'static str, body: Vec<u8>) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            // The headers, then as much body as they say there is
+            loop {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(headers_end) = text.find("\r\n\r\n") {
+                    let body_len = text[..headers_end]
+                        .lines()
+                        .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|len| len.trim().parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if request.len() >= headers_end + 4 + body_len {
+                        break;
+                    }
+                }
+                if read == 0 {
+                    break;
+                }
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len()).unwrap();
+            stream.write_all(&body).unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn test_http_text_to_speech_request() {
+        let audio = encode_wav(&[0.0; 1600], 16_000);
+        let (url, server) = stub_server("200 OK", audio.clone());
+        let tts = HttpTextToSpeech::new(HttpTtsApi::OpenAi, format!("{}/v1/", url), Some("secret".to_string()), "tts-1".to_string(), PlaybackReference::default());
+        let settings = VoiceSettings { voice: Some("nova".to_string()), rate: 1.5, ..VoiceSettings::default() };
+        tts.set_voice_settings(&settings).unwrap();
+
+        let received = tauri::async_runtime::block_on(tts.synthesize("Time to get up")).unwrap();
+        assert_eq!(received.to_vec(), audio);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /v1/audio/speech HTTP/1.1\r\n"), "{}", request);
+        assert!(request.to_lowercase().contains("authorization: bearer secret\r\n"));
+        let body: serde_json::Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["input"], "Time to get up");
+        assert_eq!(body["voice"], "nova");
+        assert_eq!(body["speed"], 1.5);
+        assert_eq!(body["model"], "tts-1");
+    }
+
+    #[test]
+    fn test_http_text_to_speech_error() {
+        let (url, server) = stub_server("401 Unauthorized", b"Invalid API key".to_vec());
+        let tts = HttpTextToSpeech::new(HttpTtsApi::ElevenLabs, url, Some("wrong".to_string()), "eleven_multilingual_v2".to_string(), PlaybackReference::default());
+
+        let error = tauri::async_runtime::block_on(tts.synthesize("Hello")).unwrap_err().to_string();
+        assert!(error.contains("401") && error.contains("Invalid API key"), "{}", error);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /v1/text-to-speech/pMsXgVXv3BLzUgSXRplE HTTP/1.1\r\n"), "{}", request);
+        assert!(request.to_lowercase().contains("xi-api-key: wrong\r\n"));
+    }
+
+    // Stands in for piper, writing part of its output before failing with the given exit code
+    #[cfg(unix)]
+    fn fake_piper(name: &str, exit_code: i32) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("sigma-{}-{}", name, std::process::id()));
+        let script = format!("#!/bin/sh\ncat > /dev/null\nwhile [ \"$1\" != \"--output_file\" ]; do shift; done\nprintf RIFF > \"$2\"\nexit {}\n", exit_code);
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    // Piper's output files still in the temp directory
+    #[cfg(unix)]
+    fn piper_outputs() -> usize {
+        let prefix = format!("sigma-piper-{}-", std::process::id());
+        fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(&prefix))
+            .count()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_piper_cleans_up() {
+        let working = fake_piper("piper-ok", 0);
+        let audio = PiperTextToSpeech::synthesize(working.clone(), PathBuf::from("voice.onnx"), 1.0, "Hello".to_string()).unwrap();
+        assert_eq!(audio.to_vec(), b"RIFF");
+        assert_eq!(piper_outputs(), 0);
+
+        let failing = fake_piper("piper-failing", 1);
+        assert!(PiperTextToSpeech::synthesize(failing.clone(), PathBuf::from("voice.onnx"), 1.0, "Hello".to_string()).is_err());
+        assert_eq!(piper_outputs(), 0);
+
+        fs::remove_file(working).unwrap();
+        fs::remove_file(failing).unwrap();
+    }
+
+    #[test]
+    fn test_scale_to_range() {
+        // macOS style rate, where half speed is 0.25 and not the 0.1 minimum
+        assert_eq!(scale_to_range(1.0, 0.1, 0.5, 2.0), 0.5);
+        assert_eq!(scale_to_range(2.0, 0.1, 0.5, 2.0), 1.0);
+        assert_eq!(scale_to_range(0.5, 0.1, 0.5, 2.0), 0.25);
+        // Clamped to what the platform allows
+        assert_eq!(scale_to_range(2.0, 0.5, 1.0, 1.5), 1.5);
+        assert_eq!(scale_to_range(0.5, 0.75, 1.0, 1.5), 0.75);
+        // speech-dispatcher rate, normal at zero
+        assert_eq!(scale_to_range(1.0, -100.0, 0.0, 100.0), 0.0);
+        assert!((scale_to_range(2.0_f32.sqrt(), -100.0, 0.0, 100.0) - 50.0).abs() < 1e-3);
+        // Out of range multipliers are clamped to the ends
+        assert_eq!(scale_to_range(8.0, -100.0, 0.0, 100.0), 100.0);
+        assert_eq!(scale_to_range(0.0, -100.0, 0.0, 100.0), -100.0);
+    }
+
+    #[test]
+    fn test_wait_for_speech_callback() {
+        let (tx, rx) = mpsc::channel();
+        tx.send(()).unwrap();
+        let start = Instant::now();
+        wait_for_speech(Some(&rx), None, Duration::from_secs(5)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_wait_for_speech_is_speaking() {
+        // Callbacks that never fire, like speech-dispatcher without end events
+        let (_tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        let is_speaking = move || -> Result<bool> { Ok(start.elapsed() < Duration::from_millis(800)) };
+        wait_for_speech(Some(&rx), Some(&is_speaking), Duration::from_secs(5)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(800));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Not speaking yet right after speak() isn't taken as finished
+        let start = Instant::now();
+        let is_speaking = || -> Result<bool> { Ok(false) };
+        wait_for_speech(None, Some(&is_speaking), Duration::from_secs(5)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_wait_for_speech_timeout() {
+        let (_tx, rx) = mpsc::channel();
+        let is_speaking = || -> Result<bool> { Ok(true) };
+        assert!(wait_for_speech(Some(&rx), Some(&is_speaking), Duration::from_millis(300)).is_err());
+
+        let (tx, rx) = mpsc::channel::<()>();
+        drop(tx);
+        assert!(wait_for_speech(Some(&rx), None, Duration::from_secs(5)).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+use crate::language::find_language;
+use crate::speech_to_text::Transcript;
+
+// Things whisper is known to "hear" in silence or background noise, usually from subtitled videos
+const HALLUCINATIONS: [&str; 6] = [
+    "thank you for watching",
+    "thanks for watching",
+    "please subscribe",
+    "subtitles by",
+    "like and subscribe",
+    "see you in the next video",
+];
+// Also common in silence, but real answers too, so only rejected when whisper isn't sure of them
+const SHORT_HALLUCINATIONS: [&str; 2] = ["you", "thank you"];
+const SHORT_HALLUCINATION_MIN_CONFIDENCE: f32 = 0.7;
+// Whisper repeating its last transcript for new audio is only caught for phrases this long, since
+// "yes" to two questions in a row is normal
+const MIN_REPEATED_TURN_WORDS: usize = 3;
+
+// Stored under the "transcriptFilter" key of the settings store
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TranscriptFilterConfig {
+    // Transcripts the STT backend is less sure of than this are asked again
+    pub min_confidence: f32,
+    pub max_no_speech_probability: f32,
+    // Said instead of asking GPT when a transcript is rejected. Empty for the spoken language's own phrase
+    pub reprompt_phrase: String,
+    // Rejected transcripts in a row before the session is ended
+    pub max_consecutive_failures: u32,
+}
+
+impl Default for TranscriptFilterConfig {
+    fn default() -> Self {
+        TranscriptFilterConfig {
+            min_confidence: 0.4,
+            max_no_speech_probability: 0.6,
+            reprompt_phrase: String::new(),
+            max_consecutive_failures: 3,
+        }
+    }
+}
+
+impl TranscriptFilterConfig {
+    pub fn reprompt_phrase(&self, language: Option<&str>) -> String {
+        let default = language.and_then(find_language).or_else(|| find_language("en")).map_or("", |language| language.reprompt);
+        // Older settings stored the English phrase, which isn't a choice the user made
+        let custom = self.reprompt_phrase.trim();
+        if custom.is_empty() || custom == "Sorry, I didn't catch that." {
+            default.to_string()
+        } else {
+            custom.to_string()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TranscriptIssue {
+    Empty,
+    // Only tags like [MUSIC], (silence) or *coughs*
+    NonSpeech,
+    LowConfidence,
+    // A known whisper hallucination, the same phrase over and over, or the last turn again
+    Hallucination,
+}
+
+// Removes [MUSIC], (applause), *sighs* and similar, leaving whatever was actually said
+fn strip_tags(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut closing = None;
+    for c in text.chars() {
+        match (closing, c) {
+            (None, '[') => closing = Some(']'),
+            (None, '(') => closing = Some(')'),
+            (None, '*') => closing = Some('*'),
+            (None, c) => stripped.push(c),
+            (Some(close), c) if c == close => closing = None,
+            _ => {}
+        }
+    }
+    stripped
+}
+
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// True when the text is a phrase of up to 4 words repeated at least 3 times, e.g. "I'm going to I'm going to I'm going to".
+// Short answers like "yes yes yes" are let through
+fn is_repeated_phrase(text: &str) -> bool {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if words.len() < 6 {
+        return false;
+    }
+    (1..=4).any(|len| {
+        let repeats = words.len() / len;
+        repeats >= 3 && words.len() % len == 0 && words.chunks(len).all(|chunk| chunk == &words[..len])
+    })
+}
+
+pub fn classify(transcript: &Transcript, config: &TranscriptFilterConfig) -> Option<TranscriptIssue> {
+    if transcript.text.trim().is_empty() {
+        return Some(TranscriptIssue::Empty);
+    }
+
+    let spoken = normalize(&strip_tags(&transcript.text));
+    if spoken.is_empty() {
+        return Some(TranscriptIssue::NonSpeech);
+    }
+
+    if HALLUCINATIONS.iter().any(|h| spoken == normalize(h)) || is_repeated_phrase(&spoken) {
+        return Some(TranscriptIssue::Hallucination);
+    }
+    let short_hallucination = SHORT_HALLUCINATIONS.iter().any(|h| spoken == normalize(h));
+    if short_hallucination && transcript.confidence.map_or(false, |c| c < SHORT_HALLUCINATION_MIN_CONFIDENCE) {
+        return Some(TranscriptIssue::Hallucination);
+    }
+
+    let unsure = transcript.confidence.map_or(false, |c| c < config.min_confidence);
+    let silent = transcript.no_speech_probability.map_or(false, |p| p > config.max_no_speech_probability);
+    if unsure || silent {
+        return Some(TranscriptIssue::LowConfidence);
+    }
+
+    None
+}
+
+// Classifies each turn's transcript, remembering the last one so a transcript repeated across
+// turns is caught too
+pub struct TranscriptFilter {
+    pub config: TranscriptFilterConfig,
+    last_spoken: Option<String>,
+    last_issue: Option<TranscriptIssue>,
+}
+
+impl TranscriptFilter {
+    pub fn new(config: TranscriptFilterConfig) -> TranscriptFilter {
+        TranscriptFilter { config, last_spoken: None, last_issue: None }
+    }
+
+    pub fn classify(&mut self, transcript: &Transcript) -> Option<TranscriptIssue> {
+        let spoken = normalize(&strip_tags(&transcript.text));
+        let mut issue = classify(transcript, &self.config);
+
+        // After "didn't catch that" the user saying the same thing again is expected
+        let asked_to_repeat = matches!(self.last_issue, Some(TranscriptIssue::LowConfidence));
+        let repeated = self.last_spoken.as_deref() == Some(spoken.as_str())
+            && spoken.split_whitespace().count() >= MIN_REPEATED_TURN_WORDS
+            && !asked_to_repeat;
+        if issue.is_none() && repeated {
+            issue = Some(TranscriptIssue::Hallucination);
+        }
+
+        if !spoken.is_empty() {
+            self.last_spoken = Some(spoken);
+        }
+        self.last_issue = issue.clone();
+        issue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::speech_to_text::Transcript;
+    use crate::transcript_filter::{classify, TranscriptFilter, TranscriptFilterConfig, TranscriptIssue};
+
+    fn transcript(text: &str, confidence: Option<f32>) -> Transcript {
+        Transcript { text: text.to_string(), confidence, ..Transcript::default() }
+    }
+
+    #[test]
+    fn test_classify_transcripts() {
+        let config = TranscriptFilterConfig::default();
+        let check = |text, confidence| classify(&transcript(text, confidence), &config);
+
+        assert_eq!(check("I made my bed", Some(0.9)), None);
+        assert_eq!(check("  ", None), Some(TranscriptIssue::Empty));
+        assert_eq!(check("[MUSIC]", None), Some(TranscriptIssue::NonSpeech));
+        assert_eq!(check(" (silence) [BLANK_AUDIO] *coughs* ", None), Some(TranscriptIssue::NonSpeech));
+        assert_eq!(check("[MUSIC] Yes I did", Some(0.9)), None);
+        assert_eq!(check("Thanks for watching!", Some(0.9)), Some(TranscriptIssue::Hallucination));
+        assert_eq!(check("I'm going to I'm going to I'm going to", Some(0.9)), Some(TranscriptIssue::Hallucination));
+        assert_eq!(check("Yes yes yes", Some(0.9)), None);
+        // "You" is a real answer when whisper is sure of it
+        assert_eq!(check("You.", Some(0.9)), None);
+        assert_eq!(check("You.", Some(0.5)), Some(TranscriptIssue::Hallucination));
+        assert_eq!(check("Yes I showered", Some(0.2)), Some(TranscriptIssue::LowConfidence));
+        // Backends that don't report confidence aren't penalised for it
+        assert_eq!(check("Yes I showered", None), None);
+
+        let noise = Transcript { no_speech_probability: Some(0.9), ..transcript("Okay", Some(0.8)) };
+        assert_eq!(classify(&noise, &config), Some(TranscriptIssue::LowConfidence));
+    }
+
+    #[test]
+    fn test_repeated_turns() {
+        let mut filter = TranscriptFilter::new(TranscriptFilterConfig::default());
+
+        assert_eq!(filter.classify(&transcript("I brushed my teeth", Some(0.9))), None);
+        // The same words for the next bit of audio is whisper stuck, not the user
+        assert_eq!(filter.classify(&transcript("I brushed my teeth.", Some(0.9))), Some(TranscriptIssue::Hallucination));
+        assert_eq!(filter.classify(&transcript("Yes", Some(0.9))), None);
+        assert_eq!(filter.classify(&transcript("Yes", Some(0.9))), None);
+
+        // Unless the user was just asked to say it again
+        assert_eq!(filter.classify(&transcript("I made my bed", Some(0.2))), Some(TranscriptIssue::LowConfidence));
+        assert_eq!(filter.classify(&transcript("I made my bed", Some(0.9))), None);
+    }
+
+    #[test]
+    fn test_reprompt_phrase_follows_language() {
+        let config = TranscriptFilterConfig::default();
+        assert_eq!(config.reprompt_phrase(Some("es")), "Perdona, no te he entendido.");
+        assert_eq!(config.reprompt_phrase(None), "Sorry, I didn't catch that.");
+
+        let custom = TranscriptFilterConfig { reprompt_phrase: "Come again?".to_string(), ..TranscriptFilterConfig::default() };
+        assert_eq!(custom.reprompt_phrase(Some("es")), "Come again?");
+        // The English phrase older settings saved still follows the language
+        let old = TranscriptFilterConfig { reprompt_phrase: "Sorry, I didn't catch that.".to_string(), ..TranscriptFilterConfig::default() };
+        assert_eq!(old.reprompt_phrase(Some("fr")), "Désolé, je n'ai pas compris.");
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+
+// Speech always dips between words, so a level that hasn't dropped for this long is noise, even
+// while the detector thinks someone is talking
+const NOISE_WINDOW_MS: u32 = 3000;
+// How quickly the floor follows that level during speech, per frame
+const NOISE_RISE_IN_SPEECH: f32 = 0.05;
+
+// Stored under the "vad" key of the settings store
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct VadConfig {
+    // Length of each analysis frame, 10-30ms
+    pub frame_ms: u32,
+    // How far above the noise floor a frame has to be to count as speech
+    pub threshold_db: f32,
+    // Frames quieter than this are never speech, however low the noise floor gets
+    pub min_energy_db: f32,
+    // Speech has to last this long before SpeechStart is emitted
+    pub speech_start_ms: u32,
+    // Silence has to last this long before SpeechEnd is emitted
+    pub speech_end_ms: u32,
+    pub high_pass_hz: f32,
+    // Longer turns are sent on as they are, as if the user had paused. None means no limit
+    pub max_utterance_ms: Option<u32>,
+}
+
+impl Default for VadConfig {
+    fn default() -> Self {
+        VadConfig {
+            frame_ms: 20,
+            threshold_db: 9.0,
+            min_energy_db: -55.0,
+            speech_start_ms: 100,
+            speech_end_ms: 1000,
+            high_pass_hz: 100.0,
+            max_utterance_ms: Some(120_000),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VadEvent {
+    // Sample offsets are counted from the first sample passed to the detector
+    SpeechStart { sample: usize },
+    SpeechEnd { sample: usize },
+}
+
+pub struct VoiceActivityDetector {
+    config: VadConfig,
+    frame_len: usize,
+    start_frames: usize,
+    end_frames: usize,
+    // High-pass filter state, kept across calls so frames join up without clicks
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+    pending: Vec<f32>,
+    samples_seen: usize,
+    noise_floor: Option<f32>,
+    // Energy of the last NOISE_WINDOW_MS of frames
+    recent_energy: VecDeque<f32>,
+    noise_window_frames: usize,
+    in_speech: bool,
+    speech_run: usize,
+    silence_run: usize,
+}
+
+fn to_db(rms: f32) -> f32 {
+    20.0 * rms.max(1e-10).log10()
+}
+
+impl VoiceActivityDetector {
+    pub fn new(config: VadConfig, sample_rate: u32) -> VoiceActivityDetector {
+        let frame_ms = config.frame_ms.clamp(10, 30);
+        let frame_len = (sample_rate as usize * frame_ms as usize / 1000).max(1);
+        let start_frames = ((config.speech_start_ms / frame_ms) as usize).max(1);
+        let end_frames = ((config.speech_end_ms / frame_ms) as usize).max(1);
+
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * config.high_pass_hz.max(1.0));
+        let dt = 1.0 / sample_rate as f32;
+
+        VoiceActivityDetector {
+            config,
+            frame_len,
+            start_frames,
+            end_frames,
+            alpha: rc / (rc + dt),
+            prev_input: 0.0,
+            prev_output: 0.0,
+            pending: Vec::with_capacity(frame_len),
+            samples_seen: 0,
+            noise_floor: None,
+            recent_energy: VecDeque::new(),
+            noise_window_frames: ((NOISE_WINDOW_MS / frame_ms) as usize).max(1),
+            in_speech: false,
+            speech_run: 0,
+            silence_run: 0,
+        }
+    }
+
+    pub fn is_speaking(&self) -> bool {
+        self.in_speech
+    }
+
+    // Used when speech was detected elsewhere (e.g. barge-in) so the detector reports when it ends
+    pub fn begin_speech(&mut self) {
+        self.in_speech = true;
+        self.speech_run = 0;
+        self.silence_run = 0;
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Vec<VadEvent> {
+        let mut events = Vec::new();
+
+        for &sample in samples {
+            let filtered = self.alpha * (self.prev_output + sample - self.prev_input);
+            self.prev_input = sample;
+            self.prev_output = filtered;
+            self.pending.push(filtered);
+
+            if self.pending.len() == self.frame_len {
+                if let Some(event) = self.process_frame() {
+                    events.push(event);
+                }
+                self.samples_seen += self.frame_len;
+                self.pending.clear();
+            }
+        }
+
+        events
+    }
+
+    fn process_frame(&mut self) -> Option<VadEvent> {
+        let rms = (self.pending.iter().map(|s| s * s).sum::<f32>() / self.frame_len as f32).sqrt();
+        let energy_db = to_db(rms);
+        let noise_floor_db = *self.noise_floor.get_or_insert(energy_db);
+
+        let is_speech = energy_db > self.config.min_energy_db
+            && energy_db > noise_floor_db + self.config.threshold_db;
+
+        if !is_speech {
+            // Follow the noise down quickly and up slowly, so speech doesn't raise the floor
+            let rate = if energy_db < noise_floor_db { 0.5 } else { 0.02 };
+            self.noise_floor = Some(noise_floor_db + (energy_db - noise_floor_db) * rate);
+        }
+
+        self.recent_energy.push_back(energy_db);
+        if self.recent_energy.len() > self.noise_window_frames {
+            self.recent_energy.pop_front();
+        }
+        if is_speech && self.recent_energy.len() == self.noise_window_frames {
+            // A step up in steady noise (a fan turning on) would otherwise hold the detector in
+            // speech forever, since the floor above only moves during silence
+            let quietest = self.recent_energy.iter().cloned().fold(f32::INFINITY, f32::min);
+            let floor = self.noise_floor.unwrap_or(quietest);
+            if quietest > floor {
+                self.noise_floor = Some(floor + (quietest - floor) * NOISE_RISE_IN_SPEECH);
+            }
+        }
+
+        if is_speech {
+            self.speech_run += 1;
+            self.silence_run = 0;
+        } else {
+            self.silence_run += 1;
+            self.speech_run = 0;
+        }
+
+        if !self.in_speech && self.speech_run >= self.start_frames {
+            self.in_speech = true;
+            let first_speech_frame = self.samples_seen + self.frame_len - self.speech_run * self.frame_len;
+            return Some(VadEvent::SpeechStart { sample: first_speech_frame });
+        }
+        if self.in_speech && self.silence_run >= self.end_frames {
+            self.in_speech = false;
+            let first_silent_frame = self.samples_seen + self.frame_len - self.silence_run * self.frame_len;
+            return Some(VadEvent::SpeechEnd { sample: first_silent_frame });
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vad::{VadConfig, VadEvent, VoiceActivityDetector};
+
+    const SAMPLE_RATE: u32 = 16_000;
+
+    fn sine(freq: f32, amplitude: f32, ms: usize) -> Vec<f32> {
+        let n = SAMPLE_RATE as usize * ms / 1000;
+        (0..n)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * freq * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    // Deterministic white noise so the tests don't need a rand dependency
+    fn noise(amplitude: f32, ms: usize, seed: u32) -> Vec<f32> {
+        let n = SAMPLE_RATE as usize * ms / 1000;
+        let mut state = seed;
+        (0..n)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                amplitude * ((state >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0)
+            })
+            .collect()
+    }
+
+    fn mix(a: &[f32], b: &[f32]) -> Vec<f32> {
+        a.iter().zip(b).map(|(x, y)| x + y).collect()
+    }
+
+    #[test]
+    fn test_silence_has_no_events() {
+        let mut vad = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        assert!(vad.process(&vec![0.0; 32_000]).is_empty());
+        assert!(!vad.is_speaking());
+    }
+
+    #[test]
+    fn test_speech_start_and_end() {
+        let mut vad = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        let mut signal = noise(0.001, 500, 1);
+        signal.extend(mix(&sine(300.0, 0.3, 1000), &noise(0.001, 1000, 2)));
+        signal.extend(noise(0.001, 1500, 3));
+
+        let events = vad.process(&signal);
+
+        assert_eq!(events.len(), 2);
+        match (&events[0], &events[1]) {
+            (VadEvent::SpeechStart { sample: start }, VadEvent::SpeechEnd { sample: end }) => {
+                assert!((*start as i64 - 8_000).abs() <= 640, "start at {}", start);
+                assert!((*end as i64 - 24_000).abs() <= 640, "end at {}", end);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_short_click_is_ignored() {
+        let mut vad = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        let mut signal = noise(0.001, 500, 1);
+        signal.extend(sine(300.0, 0.5, 40));
+        signal.extend(noise(0.001, 500, 2));
+
+        assert!(vad.process(&signal).is_empty());
+    }
+
+    #[test]
+    fn test_hangover_bridges_short_pauses() {
+        let mut vad = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        let mut signal = noise(0.001, 500, 1);
+        signal.extend(sine(300.0, 0.3, 600));
+        signal.extend(noise(0.001, 300, 2));
+        signal.extend(sine(300.0, 0.3, 600));
+        signal.extend(noise(0.001, 1500, 3));
+
+        let events = vad.process(&signal);
+
+        assert_eq!(events.len(), 2, "{:?}", events);
+    }
+
+    #[test]
+    fn test_noise_floor_adapts_to_steady_noise() {
+        let mut vad = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        let mut signal = noise(0.05, 3000, 1);
+        assert!(vad.process(&signal).is_empty());
+
+        signal = mix(&sine(300.0, 0.5, 1000), &noise(0.05, 1000, 2));
+        let events = vad.process(&signal);
+        assert!(matches!(events.first(), Some(VadEvent::SpeechStart { .. })));
+    }
+
+    #[test]
+    fn test_step_in_noise_ends_speech() {
+        let mut vad = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        let mut signal = noise(0.001, 1000, 1);
+        // A fan turning on, far above the old floor and never dropping back
+        signal.extend(noise(0.05, 8000, 2));
+
+        let events = vad.process(&signal);
+
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert!(matches!(events[1], VadEvent::SpeechEnd { .. }));
+        assert!(!vad.is_speaking());
+
+        // Speech over the new noise is still heard
+        let events = vad.process(&mix(&sine(300.0, 0.5, 1000), &noise(0.05, 1000, 3)));
+        assert!(matches!(events.first(), Some(VadEvent::SpeechStart { .. })), "{:?}", events);
+    }
+
+    #[test]
+    fn test_long_speech_with_pauses_keeps_going() {
+        let mut vad = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        let mut signal = noise(0.001, 500, 1);
+        // 10 seconds of words with short gaps between them
+        for i in 0..25 {
+            signal.extend(sine(300.0, 0.3, 300));
+            signal.extend(noise(0.001, 100, i + 2));
+        }
+
+        let events = vad.process(&signal);
+
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert!(vad.is_speaking());
+    }
+
+    #[test]
+    fn test_events_are_independent_of_chunking() {
+        let mut signal = noise(0.001, 500, 1);
+        signal.extend(sine(300.0, 0.3, 1000));
+        signal.extend(noise(0.001, 1500, 2));
+
+        let mut whole = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        let mut chunked = VoiceActivityDetector::new(VadConfig::default(), SAMPLE_RATE);
+        let chunked_events: Vec<VadEvent> = signal.chunks(333).flat_map(|c| chunked.process(c)).collect();
+
+        assert_eq!(whole.process(&signal), chunked_events);
+    }
+}
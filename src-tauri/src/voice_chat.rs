@@ -2,113 +2,443 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
-use std::thread;
-use async_openai::types::Role;
-use tauri::AppHandle;
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use futures::future::{select, Either};
+use futures::pin_mut;
+use serde::Serialize;
+use tauri::{AppHandle, Manager, State};
 use tokio::sync::Mutex;
-use tts::Tts;
-use crate::{gpt, text_to_speech, whisper};
-use crate::audio_utils::play_audio_from_wav;
-use crate::gpt::{create_chat_completion_request_msg, get_gpt_response};
-use crate::text_to_speech::speak_string;
-use crate::whisper::WHISPER_CONTEXT;
+use crate::{chat_provider, gpt, speech_to_text, text_to_speech, whisper};
+use crate::audio_utils::{play_audio_from_wav, AudioProcessingConfig, PlaybackReference};
+use crate::gpt::{create_chat_completion_request_msg, create_function_result_msg, get_gpt_response};
+use crate::history::{SessionOutcome, SessionRecorder, SessionStore};
+use crate::routine::{find_routine, RoutineProgress};
+use crate::scheduler::SharedScheduler;
+use crate::stores::get_setting;
+use crate::language::language_setting;
+use crate::text_to_speech::SpeechChunk;
+use crate::transcript_filter::{classify, TranscriptFilter, TranscriptFilterConfig};
+use crate::vad::VadConfig;
+use crate::voice_session::{VoiceSession, VoiceSessions};
 
+// GPT may update the routine a few times before it answers, but a model stuck calling functions
+// mustn't keep the user waiting forever
+const MAX_FUNCTION_ROUNDS: usize = 5;
+// A network blip is worth riding out, but a bad API key or a dead server isn't going to recover
+const MAX_STT_ERRORS: u32 = 3;
+
+// What the assistant has actually said out loud in the current turn. The history only
+// gets what was spoken, so an interrupted answer is stored truncated.
+#[derive(Default)]
+struct AssistantTurn {
+    spoken: Vec<String>,
+    in_progress: bool,
+    interrupted: bool,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserTranscript {
+    text: String,
+    // False while the user is still talking and the text may change
+    is_final: bool,
+}
+
+fn emit_user_transcript(handle: &AppHandle, text: &str, is_final: bool) {
+    let transcript = UserTranscript { text: text.to_string(), is_final };
+    if let Err(e) = handle.emit_all("user_transcript", transcript) {
+        eprintln!("Failed to emit transcript: {}", e);
+    }
+}
+
+// Shown in the window for errors the session carries on after
+fn emit_error(handle: &AppHandle, message: &str) {
+    if let Err(e) = handle.emit_all("voice_chat_error", message) {
+        eprintln!("Failed to emit error: {}", e);
+    }
+}
+
+// Starts a voice chat for the routine, stopping any chat that's already running first
 #[tauri::command]
-pub async fn start_voice_chat(handle: AppHandle) {
-    let tts = Tts::default().unwrap();
-    let tts_clone = tts.clone();
-    let handle_clone = handle.clone();
-    let initial_speech_handle = tokio::spawn(async { text_to_speech::initial_speech(handle_clone, tts_clone).await });
+pub async fn start_voice_chat(handle: AppHandle, sessions: State<'_, VoiceSessions>, routine_id: Option<String>) -> Result<(), String> {
+    let ticket = sessions.replace().await;
+
+    // Set up without holding the sessions lock, so stop_voice_chat isn't stuck behind model loading
+    let mut session = VoiceSession::new();
+    if let Err(e) = setup_voice_chat(handle, &mut session, routine_id).await {
+        // The greeting may already be playing
+        session.stop().await;
+        return Err(e);
+    }
+    sessions.store(ticket, session).await;
+    Ok(())
+}
+
+// Starts the session's tasks. Anything already started is stopped by the caller on an error
+async fn setup_voice_chat(handle: AppHandle, session: &mut VoiceSession, routine_id: Option<String>) -> Result<(), String> {
+    let token = session.token();
+
+    // Anything the TTS backend plays itself is cancelled out of the mic. OS voices play outside
+    // the app, so for those only the echo gate keeps the assistant from interrupting itself
+    let playback = PlaybackReference::default();
+    let tts = text_to_speech::from_settings(&handle, playback.clone()).map_err(|e| e.to_string())?;
+    // None when the language is detected from what the user says
+    let language = language_setting(&handle);
+    if let Some(language) = &language {
+        if let Err(e) = tts.set_language(language) {
+            eprintln!("Failed to pick a voice for {}: {}", language, e);
+        }
+    }
 
     let (audio_tx, mut audio_rx) = tauri::async_runtime::channel(20);
     let (user_string_tx, mut user_string_rx) = tauri::async_runtime::channel(20);
     let (gpt_string_tx, mut gpt_string_rx) = tauri::async_runtime::channel(20);
-    let (resume_stream_tx, resume_stream_rx) = tauri::async_runtime::channel(1);
-    let should_quit = Arc::new(AtomicBool::new(false));
+    let (barge_in_tx, mut barge_in_rx) = tauri::async_runtime::channel(1);
+    let assistant_speaking = Arc::new(AtomicBool::new(false));
+    let assistant_turn = Arc::new(Mutex::new(AssistantTurn::default()));
+    // Cancelled when the user talks over the reply being generated, so the rest of it isn't said
+    let reply_token = Arc::new(Mutex::new(token.child_token()));
 
-    let initial_messages = gpt::messages_setup(handle.clone()).await;
+    let tts_clone = tts.clone();
+    let handle_clone = handle.clone();
+    let language_clone = language.clone();
+    let assistant_speaking_clone = assistant_speaking.clone();
+    // Greet the user while the speech model loads. The mic may open before it's done, so the echo
+    // gate is told the assistant is talking
+    session.spawn(async move {
+        assistant_speaking_clone.store(true, Relaxed);
+        text_to_speech::initial_speech(handle_clone, tts_clone, language_clone).await;
+        assistant_speaking_clone.store(false, Relaxed);
+    });
+
+    let routine = find_routine(&handle, routine_id.as_deref());
+    println!("Starting routine: {}", routine.name);
+    let progress = RoutineProgress::new(&routine);
+    let initial_messages = gpt::messages_setup(&routine, &progress, language.as_deref());
+    handle.emit_all("routine_progress", progress.clone()).expect("Failed to emit routine progress");
+    let progress = Arc::new(Mutex::new(progress));
     let messages = Arc::new(Mutex::new(initial_messages));
     let messages_clone = messages.clone();
+    let recorder = Arc::new(SessionRecorder::new(SessionStore::from_handle(&handle).map_err(|e| e.to_string())?, &routine.id));
 
 
-    whisper::init_whisper_context().await;
-    let ctx = WHISPER_CONTEXT.get().expect("WhisperContext not initialized");
-    let mut state = ctx.create_state().expect("failed to create key");
+    // Setup errors (e.g. no speech model installed) are shown in the window rather than panicking
+    let chat_provider = chat_provider::from_settings(&handle).map_err(|e| e.to_string())?;
+    let mut stt = speech_to_text::from_settings(&handle).await.map_err(|e| e.to_string())?;
 
-    initial_speech_handle.await.unwrap();
-
-    let should_quit_clone = should_quit.clone();
+    let capture_config = whisper::CaptureConfig {
+        vad: get_setting::<VadConfig>(handle.clone(), "vad").unwrap_or_default(),
+        // Unset means the system default microphone
+        input_device: get_setting::<String>(handle.clone(), "inputDevice").filter(|name| !name.is_empty()),
+        // Show what the user is saying while they say it, rather than only once they've finished
+        partial_interval_ms: get_setting::<bool>(handle.clone(), "streamingTranscription")
+            .unwrap_or(true)
+            .then(|| if stt.is_remote() { whisper::REMOTE_PARTIAL_INTERVAL_MS } else { whisper::PARTIAL_INTERVAL_MS }),
+        processing: get_setting::<AudioProcessingConfig>(handle.clone(), "audioProcessing").unwrap_or_default(),
+    };
+    let assistant_speaking_clone = assistant_speaking.clone();
+    let chime_playback = playback.clone();
     // Start the thread that sends audio to the channel
-    thread::spawn(|| {
-        whisper::send_system_audio_to_channel(audio_tx, resume_stream_rx, should_quit_clone);
+    session.spawn_capture(move |should_quit| {
+        whisper::send_system_audio_to_channel(audio_tx, barge_in_tx, assistant_speaking_clone, should_quit, capture_config, playback);
     });
 
-    let should_quit_clone = should_quit.clone();
+    let filter_config = get_setting::<TranscriptFilterConfig>(handle.clone(), "transcriptFilter").unwrap_or_default();
+    let mut filter = TranscriptFilter::new(filter_config);
+    let token_clone = token.clone();
+    let recorder_clone = recorder.clone();
+    let handle_clone = handle.clone();
+    let routine_id = routine.id.clone();
+    let tts_clone = tts.clone();
+    let mut voice_language = language.clone();
+    let reprompt_tx = gpt_string_tx.clone();
     // Start the thread that takes audio from the channel and sends it to STT
-    let _ = tauri::async_runtime::spawn(async move {
+    session.spawn(async move {
+        let mut consecutive_failures = 0;
+        let mut stt_errors = 0;
         loop {
-            if let Some(audio) = audio_rx.recv().await {
-                let text = whisper::speech_to_text(&audio, &mut state);
+            if let Some(mut utterance) = audio_rx.recv().await {
+                // A newer partial makes an older one pointless, but the final utterance is never skipped
+                while !utterance.is_final {
+                    match audio_rx.try_recv() {
+                        Ok(next) => utterance = next,
+                        Err(_) => break,
+                    }
+                }
+
+                if !utterance.is_final {
+                    match stt.transcribe(&utterance.samples, utterance.sample_rate).await {
+                        // A rejected partial is left off rather than flashing up a hallucination
+                        Ok(partial) if classify(&partial, &filter.config).is_none() => {
+                            let text = partial.text.trim();
+                            let text = if utterance.truncated { format!("… {}", text) } else { text.to_string() };
+                            emit_user_transcript(&handle_clone, &text, false);
+                        }
+                        Ok(_) => {}
+                        Err(e) => eprintln!("Failed to transcribe partial audio: {}", e),
+                    }
+                    continue;
+                }
+
+                let transcript = match stt.transcribe(&utterance.samples, utterance.sample_rate).await {
+                    Ok(transcript) => transcript,
+                    Err(e) => {
+                        stt_errors += 1;
+                        eprintln!("Failed to transcribe audio, {} in a row: {}", stt_errors, e);
+                        emit_user_transcript(&handle_clone, "", true);
+                        emit_error(&handle_clone, &format!("Failed to transcribe what you said: {}", e));
+                        if stt_errors >= MAX_STT_ERRORS {
+                            println!("Speech to text keeps failing, ending the session");
+                            recorder_clone.finish(SessionOutcome::Abandoned);
+                            token_clone.cancel();
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                stt_errors = 0;
+
+                // Noise, hallucinations and mumbling are handled here rather than costing a GPT call
+                if let Some(issue) = filter.classify(&transcript) {
+                    emit_user_transcript(&handle_clone, "", true);
+                    consecutive_failures += 1;
+                    println!("Rejected transcript {:?} ({:?}), {} in a row", transcript.text, issue, consecutive_failures);
+                    if consecutive_failures >= filter.config.max_consecutive_failures {
+                        println!("Too many failed transcripts, ending the session");
+                        recorder_clone.finish(SessionOutcome::Abandoned);
+                        token_clone.cancel();
+                        break;
+                    }
+                    // The TTS task only goes away when the session is stopped
+                    let reprompt = filter.config.reprompt_phrase(voice_language.as_deref());
+                    if reprompt_tx.send(SpeechChunk::Sentence(reprompt)).await.is_err()
+                        || reprompt_tx.send(SpeechChunk::EndOfTurn).await.is_err()
+                    {
+                        break;
+                    }
+                    continue;
+                }
+                consecutive_failures = 0;
+
+                // When detecting the language, answer in a voice for whatever the user just spoke
+                if let Some(detected) = transcript.language.clone().filter(|l| voice_language.as_ref() != Some(l)) {
+                    if let Err(e) = tts_clone.set_language(&detected) {
+                        eprintln!("Failed to pick a voice for {}: {}", detected, e);
+                    }
+                    voice_language = Some(detected);
+                }
+                let text = transcript.text;
                 println!("User: {}", text.clone());
+                emit_user_transcript(&handle_clone, text.trim(), true);
+                recorder_clone.record_turn("user", &text, transcript.confidence);
+                // The user has answered, so the scheduler can stop reminding them
+                if let Some(scheduler) = handle_clone.try_state::<SharedScheduler>() {
+                    scheduler.lock().unwrap().acknowledge(&routine_id);
+                }
 
                 let new_message = create_chat_completion_request_msg(text.clone(), Role::User);
                 messages_clone.lock().await.push(new_message);
-                user_string_tx.send(text.clone()).await.expect("Failed to send text to channel");
-            }
-            if should_quit_clone.load(Relaxed) {
-                break;
+                if user_string_tx.send(text.clone()).await.is_err() {
+                    break;
+                }
             }
         }
     });
 
 
-    let should_quit_clone = should_quit.clone();
+    let token_clone = token.clone();
+    let messages_clone = messages.clone();
+    let handle_clone = handle.clone();
+    let reply_token_clone = reply_token.clone();
     // Start the thread that takes the STT response and sends it to GPT
-    let _ = tauri::async_runtime::spawn(async move {
-        loop {
+    session.spawn(async move {
+        'conversation: loop {
             if let Some(_user_string) = user_string_rx.recv().await {
-                let messages_clone = messages.lock().await.clone();
+                let turn_token = token_clone.child_token();
+                *reply_token_clone.lock().await = turn_token.clone();
+                // GPT may update the routine several times before it says anything
+                let mut function_rounds = 0;
+                loop {
+                    let history = messages_clone.lock().await.clone();
+
+                    let reply = get_gpt_response(chat_provider.as_ref(), history, &gpt_string_tx);
+                    let interrupted = turn_token.cancelled();
+                    pin_mut!(reply, interrupted);
+                    let reply = match select(reply, interrupted).await {
+                        Either::Left((reply, _)) => reply,
+                        Either::Right(_) => {
+                            // Anything it had already sent is dropped by the TTS task, up to this end of the turn
+                            println!("Stopped generating the reply the user talked over");
+                            if gpt_string_tx.send(SpeechChunk::EndOfTurn).await.is_err() {
+                                break 'conversation;
+                            }
+                            break;
+                        }
+                    };
+                    let new_bot_message = match reply {
+                        Ok(message) => message,
+                        Err(e) => {
+                            // Whatever was said before the error is still recorded, and the user can try again
+                            eprintln!("Failed to get GPT response: {}", e);
+                            emit_error(&handle_clone, &format!("Failed to get a reply: {}", e));
+                            if gpt_string_tx.send(SpeechChunk::EndOfTurn).await.is_err() {
+                                break 'conversation;
+                            }
+                            break;
+                        }
+                    };
 
-                let new_bot_message = get_gpt_response(messages_clone).await.expect("Failed to get GPT response");
+                    if new_bot_message.role == Role::System {
+                        // The TTS task ends the session once the goodbye has been said
+                        println!("Sending quit signal");
+                        if gpt_string_tx.send(SpeechChunk::EndOfSession).await.is_err() {
+                            token_clone.cancel();
+                        }
+                        break 'conversation;
+                    }
 
-                if new_bot_message.role == Role::System {
-                    println!("Sending quit signal");
-                    should_quit_clone.store(true, Relaxed);
-                    play_audio_from_wav(PathBuf::from("assets/audio/session_complete.wav"));
+                    if let Some(function_call) = new_bot_message.function_call.clone() {
+                        function_rounds += 1;
+                        let result = {
+                            let mut progress = progress.lock().await;
+                            let result = progress
+                                .apply_function_call(&function_call.name, &function_call.arguments)
+                                .unwrap_or_else(|e| format!("Error: {}", e));
+                            handle_clone.emit_all("routine_progress", progress.clone()).expect("Failed to emit routine progress");
+                            result
+                        };
+                        println!("Function call: {}({}) -> {}", function_call.name, function_call.arguments, result);
+
+                        let result_message = match create_function_result_msg(&function_call.name, result) {
+                            Ok(message) => message,
+                            Err(e) => {
+                                eprintln!("Failed to create the result of {}: {}", function_call.name, e);
+                                break;
+                            }
+                        };
+                        let mut messages = messages_clone.lock().await;
+                        messages.push(new_bot_message);
+                        messages.push(result_message);
+                        if function_rounds >= MAX_FUNCTION_ROUNDS {
+                            eprintln!("GPT made {} function calls without answering, waiting for the user", function_rounds);
+                            break;
+                        }
+                        continue;
+                    }
+
+                    // The TTS thread adds the message to the history once it has been spoken
+                    println!("Bot: {}", new_bot_message.content.as_deref().unwrap_or_default());
+                    if gpt_string_tx.send(SpeechChunk::EndOfTurn).await.is_err() {
+                        break 'conversation;
+                    }
                     break;
                 }
+            }
+        }
+    });
 
-                println!("Bot: {}", new_bot_message.content.as_ref().unwrap());
-                messages.lock().await.push(new_bot_message.clone());
+    let tts_clone = tts.clone();
+    let assistant_turn_clone = assistant_turn.clone();
+    let messages_clone = messages.clone();
+    let recorder_clone = recorder.clone();
+    // Start the thread that stops the assistant when the user talks over it
+    session.spawn(async move {
+        while let Some(()) = barge_in_rx.recv().await {
+            let mut turn = assistant_turn_clone.lock().await;
+            if !turn.in_progress || turn.interrupted {
+                continue;
+            }
+            turn.interrupted = true;
+            reply_token.lock().await.cancel();
+            if let Err(e) = tts_clone.stop() {
+                eprintln!("Failed to stop speech: {}", e);
+            }
 
-                gpt_string_tx.send(new_bot_message.content.unwrap()).await.expect("Failed to send message to channel");
+            if !turn.spoken.is_empty() {
+                let partial = format!("{} ...", turn.spoken.join(" "));
+                println!("Bot (interrupted): {}", partial);
+                push_assistant_message(&messages_clone, &recorder_clone, partial).await;
             }
         }
     });
 
-    let should_quit_clone = should_quit.clone();
     let tts_clone = tts.clone();
+    let recorder_clone = recorder.clone();
+    let token_clone = token.clone();
     // Start the thread that takes the GPT response and sends it to TTS
-    let _ = tauri::async_runtime::spawn(async move {
+    session.spawn(async move {
         loop {
-            if let Some(gpt_response) = gpt_string_rx.recv().await {
-                let tts_cloned = tts_clone.clone();
-                let join_handle = thread::spawn(move || {
-                    speak_string(&gpt_response, tts_cloned).expect("Failed to speak string");
-                });
-
-                join_handle.join().expect("Failed to join thread");
-                // let bot_message_audio = text_to_speech("pMsXgVXv3BLzUgSXRplE", gpt_response).await.expect("Unable to run TTS");
-                // play_audio_bytes(bot_message_audio);
-                resume_stream_tx.send(true).await.expect("Failed to send pause_stream message");
-            }
-            if should_quit_clone.load(Relaxed) {
-                break;
+            match gpt_string_rx.recv().await {
+                Some(SpeechChunk::Sentence(sentence)) => {
+                    {
+                        let mut turn = assistant_turn.lock().await;
+                        if turn.interrupted {
+                            // Drop the rest of an answer the user has already talked over
+                            continue;
+                        }
+                        turn.in_progress = true;
+                    }
+                    assistant_speaking.store(true, Relaxed);
+
+                    if let Err(e) = tts_clone.speak(&sentence).await {
+                        eprintln!("Failed to speak {:?}: {}", sentence, e);
+                    }
+
+                    let mut turn = assistant_turn.lock().await;
+                    if !turn.interrupted {
+                        turn.spoken.push(sentence);
+                    }
+                }
+                Some(SpeechChunk::EndOfTurn) => {
+                    let mut turn = assistant_turn.lock().await;
+                    if !turn.interrupted && !turn.spoken.is_empty() {
+                        push_assistant_message(&messages, &recorder, turn.spoken.join(" ")).await;
+                    }
+                    *turn = AssistantTurn::default();
+                    assistant_speaking.store(false, Relaxed);
+                }
+                Some(SpeechChunk::EndOfSession) => {
+                    recorder.finish(SessionOutcome::Completed);
+                    // Through the tap, so the echo canceller hears it like the voice
+                    assistant_speaking.store(true, Relaxed);
+                    let playback = chime_playback.clone();
+                    let chime = tauri::async_runtime::spawn_blocking(move || {
+                        play_audio_from_wav(PathBuf::from("assets/audio/session_complete.wav"), Some(&playback))
+                    });
+                    match chime.await {
+                        Ok(Err(e)) => eprintln!("Failed to play the session complete chime: {}", e),
+                        Err(e) => eprintln!("Failed to play the session complete chime: {}", e),
+                        Ok(Ok(())) => {}
+                    }
+                    token_clone.cancel();
+                    break;
+                }
+                None => break,
             }
         }
     });
 
-    // save the messages to
+    session.on_cancel(async move {
+        if let Err(e) = tts.stop() {
+            eprintln!("Failed to stop speech: {}", e);
+        }
+        // Stopped or closed before the routine was done
+        if !recorder_clone.is_finished() {
+            recorder_clone.finish(SessionOutcome::Abandoned);
+        }
+    });
 
+    Ok(())
 }
 
+#[tauri::command]
+pub async fn stop_voice_chat(sessions: State<'_, VoiceSessions>) -> Result<(), String> {
+    sessions.stop().await;
+    Ok(())
+}
+
+async fn push_assistant_message(messages: &Mutex<Vec<ChatCompletionRequestMessage>>, recorder: &SessionRecorder, content: String) {
+    recorder.record_turn("assistant", &content, None);
+    let new_message = create_chat_completion_request_msg(content, Role::Assistant);
+    messages.lock().await.push(new_message);
+}
@@ -0,0 +1,218 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread;
+use futures::future::select;
+use futures::pin_mut;
+use tauri::async_runtime::JoinHandle;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+// Everything a running voice chat has started. Cancelling the token ends every task at its next
+// await, and stop() waits for all of them, so nothing keeps using the mic after a session ends
+pub struct VoiceSession {
+    token: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+    // The capture thread blocks on the mic, so it watches a flag rather than the token
+    should_quit: Arc<AtomicBool>,
+    capture: Option<thread::JoinHandle<()>>,
+}
+
+impl VoiceSession {
+    pub fn new() -> VoiceSession {
+        VoiceSession { token: CancellationToken::new(), tasks: vec![], should_quit: Arc::new(AtomicBool::new(false)), capture: None }
+    }
+
+    // Tasks cancel this to end the session themselves, e.g. when the routine is done
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.token.is_cancelled()
+    }
+
+    // Runs the task until it finishes or the session is cancelled, whichever is first
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&mut self, task: F) {
+        let token = self.token.clone();
+        self.tasks.push(tauri::async_runtime::spawn(async move {
+            let cancelled = token.cancelled();
+            pin_mut!(cancelled, task);
+            select(cancelled, task).await;
+        }));
+    }
+
+    // Runs on cancellation, for cleanup like stopping speech that's already playing
+    pub fn on_cancel<F: Future<Output = ()> + Send + 'static>(&mut self, cleanup: F) {
+        let token = self.token.clone();
+        self.tasks.push(tauri::async_runtime::spawn(async move {
+            token.cancelled().await;
+            cleanup.await;
+        }));
+    }
+
+    pub fn spawn_capture<F: FnOnce(Arc<AtomicBool>) + Send + 'static>(&mut self, capture: F) {
+        let should_quit = self.should_quit.clone();
+        self.capture = Some(thread::spawn(move || capture(should_quit)));
+
+        let should_quit = self.should_quit.clone();
+        self.on_cancel(async move { should_quit.store(true, SeqCst) });
+    }
+
+    pub async fn stop(mut self) {
+        self.token.cancel();
+        for task in self.tasks.drain(..) {
+            if let Err(e) = task.await {
+                eprintln!("Voice chat task failed: {}", e);
+            }
+        }
+
+        // on_cancel has set should_quit by now
+        if let Some(capture) = self.capture.take() {
+            let joined = tauri::async_runtime::spawn_blocking(move || capture.join().is_ok()).await;
+            if !matches!(joined, Ok(true)) {
+                eprintln!("The audio capture thread panicked");
+            }
+        }
+    }
+}
+
+// Managed state holding the current session, so there's never more than one
+#[derive(Default)]
+pub struct VoiceSessions {
+    current: Mutex<Option<VoiceSession>>,
+    // Bumped by every start and stop, since a session is set up without holding the lock and a
+    // newer request may come in meanwhile
+    requests: AtomicUsize,
+}
+
+impl VoiceSessions {
+    // Ends the current session for a new one, returning the ticket to store the new one with
+    pub async fn replace(&self) -> usize {
+        let ticket = self.requests.fetch_add(1, SeqCst) + 1;
+        self.stop_current().await;
+        ticket
+    }
+
+    // Keeps the session unless it was stopped or replaced while being set up, in which case it's
+    // stopped instead
+    pub async fn store(&self, ticket: usize, session: VoiceSession) {
+        {
+            let mut current = self.current.lock().await;
+            if self.requests.load(SeqCst) == ticket {
+                *current = Some(session);
+                return;
+            }
+        }
+        println!("The voice chat was stopped while starting");
+        session.stop().await;
+    }
+
+    // Ends the current session if there is one, returning once it has been torn down
+    pub async fn stop(&self) {
+        self.requests.fetch_add(1, SeqCst);
+        self.stop_current().await;
+    }
+
+    async fn stop_current(&self) {
+        let session = self.current.lock().await.take();
+        if let Some(session) = session {
+            if session.is_running() {
+                println!("Stopping the voice chat");
+            }
+            session.stop().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::time::Duration;
+    use futures::executor::block_on;
+    use crate::voice_session::{VoiceSession, VoiceSessions};
+
+    #[test]
+    fn test_stop_ends_blocked_tasks() {
+        let mut session = VoiceSession::new();
+        let (tx, mut rx) = tauri::async_runtime::channel::<()>(1);
+        let received = Arc::new(AtomicUsize::new(0));
+        let received_clone = received.clone();
+        // Blocks on recv() forever, like the STT and TTS loops while the user is quiet
+        session.spawn(async move {
+            while rx.recv().await.is_some() {
+                received_clone.fetch_add(1, SeqCst);
+            }
+        });
+
+        let cleaned_up = Arc::new(AtomicUsize::new(0));
+        let cleaned_up_clone = cleaned_up.clone();
+        session.on_cancel(async move {
+            cleaned_up_clone.fetch_add(1, SeqCst);
+        });
+
+        let captured = Arc::new(AtomicUsize::new(0));
+        let captured_clone = captured.clone();
+        session.spawn_capture(move |should_quit| {
+            while !should_quit.load(SeqCst) {
+                captured_clone.fetch_add(1, SeqCst);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        block_on(session.stop());
+        assert_eq!(cleaned_up.load(SeqCst), 1);
+        assert_eq!(received.load(SeqCst), 0);
+        // The capture thread has exited, so nothing touches the mic after stop()
+        let captures = captured.load(SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(captured.load(SeqCst), captures);
+        // The tasks are gone, along with their end of the channel
+        assert!(tx.try_send(()).is_err());
+    }
+
+    #[test]
+    fn test_stopped_while_starting() {
+        let sessions = VoiceSessions::default();
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let start = || {
+            let mut session = VoiceSession::new();
+            let stopped = stopped.clone();
+            session.on_cancel(async move {
+                stopped.fetch_add(1, SeqCst);
+            });
+            session
+        };
+
+        let ticket = block_on(sessions.replace());
+        let session = start();
+        // stop_voice_chat while the speech model was loading
+        block_on(sessions.stop());
+        block_on(sessions.store(ticket, session));
+        assert!(block_on(sessions.current.lock()).is_none());
+        assert_eq!(stopped.load(SeqCst), 1);
+
+        // A second start before the first has finished setting up wins
+        let first = block_on(sessions.replace());
+        let second = block_on(sessions.replace());
+        block_on(sessions.store(second, start()));
+        block_on(sessions.store(first, start()));
+        assert_eq!(stopped.load(SeqCst), 2);
+        assert!(block_on(sessions.current.lock()).as_ref().map_or(false, |session| session.is_running()));
+
+        block_on(sessions.stop());
+        assert_eq!(stopped.load(SeqCst), 3);
+    }
+
+    #[test]
+    fn test_session_ends_itself() {
+        let mut session = VoiceSession::new();
+        let token = session.token();
+        session.spawn(async move { token.cancel() });
+        block_on(session.token().cancelled());
+        assert!(!session.is_running());
+    }
+}
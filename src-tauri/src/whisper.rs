@@ -3,161 +3,435 @@ extern crate cpal;
 extern crate ringbuf;
 
 use std::mem::MaybeUninit;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use ringbuf::{Consumer, SharedRb};
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperState};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use ringbuf::{Consumer, Producer, SharedRb};
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperError, WhisperState};
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 use std::thread::sleep;
 use std::time::Duration;
-use anyhow::Error;
-use cpal::{Stream, StreamConfig};
+use anyhow::{anyhow, Error, Result};
+use cpal::{Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
 use futures::executor::block_on;
-use tauri::async_runtime::{Receiver, Sender};
-use once_cell::sync::OnceCell;
-use std::path::Path;
-use crate::audio_utils;
-use crate::audio_utils::{convert_stereo_to_mono_audio, make_audio_louder};
-
-pub const LATENCY_MS: f32 = 7000.0;
-pub static WHISPER_CONTEXT: OnceCell<WhisperContext> = OnceCell::new();
-
-pub async fn init_whisper_context() {
-    let whisper_path_str = "src/ggml-base.en.bin";
-    let whisper_path = Path::new(whisper_path_str);
-    if !whisper_path.exists() && !whisper_path.is_file() {
-        panic!("expected a whisper directory")
+use tauri::async_runtime::Sender;
+use once_cell::sync::Lazy;
+use std::path::{Path, PathBuf};
+use crate::audio_input::find_input_device;
+use crate::audio_utils::{downmix_frame, split_at_pauses, AudioProcessingConfig, EchoGate, MicProcessor, PlaybackReference, ProcessedMic, StreamResampler, WHISPER_SAMPLE_RATE};
+use crate::speech_to_text::{TranscriptSegment, TranscriptToken};
+use crate::vad::{VadConfig, VadEvent, VoiceActivityDetector};
+
+// The capture loop empties the ring buffer every 100ms, so it only has to cover a stall
+const RING_BUFFER_MS: f32 = 2000.0;
+// whisper works on 30 second windows, longer audio is transcribed a window at a time
+const WHISPER_CHUNK_MS: usize = 30_000;
+// The user has to be this much louder than the assistant's echo for this many 100ms checks in a row
+const BARGE_IN_ENERGY_RATIO: f32 = 3.0;
+const BARGE_IN_MIN_CHECKS: usize = 3;
+const BARGE_IN_WINDOW_MS: f32 = 300.0;
+// Audio kept from before the VAD decided speech started, so the first word isn't clipped
+const PRE_ROLL_MS: f32 = 300.0;
+// How often what the user has said so far is transcribed while they're still talking. Each partial
+// is a request to a remote backend, so those get fewer
+pub const PARTIAL_INTERVAL_MS: f32 = 1000.0;
+pub const REMOTE_PARTIAL_INTERVAL_MS: f32 = 3000.0;
+// Partials only cover the end of a long utterance, so each costs the same however long the user talks
+const PARTIAL_WINDOW_MS: f32 = 5000.0;
+// Each model is loaded once and kept for the life of the app, so switching models in the
+// settings doesn't need a restart
+static WHISPER_CONTEXTS: Lazy<Mutex<HashMap<PathBuf, &'static WhisperContext>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn load_whisper_context(model_path: &Path) -> Result<&'static WhisperContext> {
+    let mut contexts = WHISPER_CONTEXTS.lock().unwrap();
+    if let Some(ctx) = contexts.get(model_path) {
+        return Ok(ctx);
     }
-    let ctx = WhisperContext::new(whisper_path_str).expect("Failed to open model");
-    WHISPER_CONTEXT.set(ctx).expect("Failed to set WhisperContext");
+
+    let path_str = model_path.to_str().ok_or(anyhow!("Invalid model path {:?}", model_path))?;
+    let ctx = WhisperContext::new(path_str).map_err(|e| anyhow!("Failed to load whisper model {:?}: {:?}", model_path, e))?;
+    let ctx: &'static WhisperContext = Box::leak(Box::new(ctx));
+    contexts.insert(model_path.to_path_buf(), ctx);
+    Ok(ctx)
 }
 
 
-pub fn send_system_audio_to_channel(audio_tx: Sender<Vec<f32>>, mut resume_channel_rx: Receiver<bool>, should_quit: Arc<AtomicBool>) {
-    let (config, mut consumer, input_stream) = setup_audio().expect("Failed to setup audio");
+// A turn of the user's speech. Partial ones are sent while they're still talking, so the window can
+// show what's been heard so far, and the final one once the VAD decides they've finished
+pub struct Utterance {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub is_final: bool,
+    // A partial that only has the last few seconds of what's been said
+    pub truncated: bool,
+}
+
+// Read from the settings when the voice chat starts
+pub struct CaptureConfig {
+    pub vad: VadConfig,
+    // None for the system default microphone
+    pub input_device: Option<String>,
+    // How often to send partial utterances while the user is talking, None to only send final ones
+    pub partial_interval_ms: Option<f32>,
+    pub processing: AudioProcessingConfig,
+}
+
+type SampleConsumer = Consumer<f32, Arc<SharedRb<f32, Vec<MaybeUninit<f32>>>>>;
+type SampleProducer = Producer<f32, Arc<SharedRb<f32, Vec<MaybeUninit<f32>>>>>;
+
+pub fn send_system_audio_to_channel(audio_tx: Sender<Utterance>, barge_in_tx: Sender<()>, assistant_speaking: Arc<AtomicBool>, should_quit: Arc<AtomicBool>, capture_config: CaptureConfig, playback: PlaybackReference) {
+    let CaptureConfig { vad: vad_config, input_device, partial_interval_ms, processing } = capture_config;
+    // Set by the stream's error callback, e.g. when the microphone is unplugged
+    let stream_failed = Arc::new(AtomicBool::new(false));
+    let (mut config, mut consumer, mut input_stream) = match open_input(input_device.as_deref(), &stream_failed, &should_quit) {
+        Some(input) => input,
+        None => return,
+    };
 
-    // Ensure the initial speech is finished before starting the input stream
-    input_stream.play().expect("Failed to play input stream");
     // Remove the initial samples
     consumer.clear();
     sleep(Duration::from_millis(2000));
 
+    // Everything after the resampler runs at whisper's rate, whatever the microphone's is
+    let mut resampler = match StreamResampler::new(config.sample_rate.0, WHISPER_SAMPLE_RATE) {
+        Ok(resampler) => resampler,
+        Err(e) => {
+            eprintln!("Failed to create resampler: {}", e);
+            return;
+        }
+    };
+    let sampling_freq = WHISPER_SAMPLE_RATE as f32;
+    let pre_roll_len = ((PRE_ROLL_MS + vad_config.speech_start_ms as f32) / 1000.0 * sampling_freq) as usize;
+    let max_utterance_len = vad_config.max_utterance_ms.map(|ms| (ms as f32 / 1000.0 * sampling_freq) as usize);
+    let barge_in_window_len = (BARGE_IN_WINDOW_MS / 1000.0 * sampling_freq) as usize;
+    let partial_interval_len = partial_interval_ms.map(|ms| (ms / 1000.0 * sampling_freq) as usize);
+    let partial_window_len = (PARTIAL_WINDOW_MS / 1000.0 * sampling_freq) as usize;
+
+    let mut vad = VoiceActivityDetector::new(vad_config.clone(), sampling_freq as u32);
+    let mut mic_processor = MicProcessor::new(&processing, WHISPER_SAMPLE_RATE);
+    let mut echo_gate = EchoGate::new(BARGE_IN_ENERGY_RATIO);
+    let mut barge_in_checks = 0;
+    // The gate learns a new echo level each time the assistant starts talking
+    let mut was_speaking = false;
+    // The current utterance, plus a little audio from before it started
+    let mut utterance: Vec<f32> = Vec::new();
+    // The last barge-in window, as the gate hears it and as it'd be transcribed
+    let mut recent: Vec<f32> = Vec::new();
+    let mut recent_levelled: Vec<f32> = Vec::new();
+    // Total number of samples given to the VAD, so its event offsets can be mapped into the utterance
+    let mut fed_to_vad: usize = 0;
+    // Samples heard since the last partial utterance was sent
+    let mut since_partial: usize = 0;
+
     loop {
-        let samples: Vec<f32> = consumer.iter().map(|x| *x).collect();
-        // TODO: Instead of removing every second sample, just set the input data fn to only push every second sample
-        let samples = convert_stereo_to_mono_audio(samples).unwrap();
-        let mut samples = make_audio_louder(&samples, 2.0);
-
-        let sampling_freq = config.sample_rate.0 as f32 / 2.0; // TODO: Divide by 2 because of stereo to mono
-
-        if audio_utils::vad_simple(&mut samples, sampling_freq as usize, 1000) {
-            // the last 1000ms of audio was silent and there was talking before it
-            println!("Speech detected! Pausing input stream...");
-            input_stream.pause().expect("Failed to pause input stream");
-            block_on(async { audio_tx.send(samples).await.expect("Failed to send audio to channel") });
-            consumer.clear();
-
-            // Wait for the resume_stream message
-            loop {
-                if let Some(resume_stream) = block_on(resume_channel_rx.recv()) {
-                    if resume_stream == true {
-                        println!("Resuming input stream...");
-                        input_stream.play().expect("Failed to play input stream");
-                        break;
+        if should_quit.load(Relaxed) {
+            break;
+        }
+        sleep(Duration::from_millis(100));
+
+        if stream_failed.swap(false, Relaxed) {
+            println!("Input stream failed, reopening it");
+            drop(input_stream);
+            (config, consumer, input_stream) = match open_input(input_device.as_deref(), &stream_failed, &should_quit) {
+                Some(input) => input,
+                None => break,
+            };
+
+            // The fallback device may run at a different rate, and whatever was half heard is lost anyway
+            resampler = match StreamResampler::new(config.sample_rate.0, WHISPER_SAMPLE_RATE) {
+                Ok(resampler) => resampler,
+                Err(e) => {
+                    eprintln!("Failed to create resampler: {}", e);
+                    break;
+                }
+            };
+            vad = VoiceActivityDetector::new(vad_config.clone(), sampling_freq as u32);
+            // The echo has a different path to a different mic
+            mic_processor.reset_echo();
+            utterance.clear();
+            recent.clear();
+            recent_levelled.clear();
+            fed_to_vad = 0;
+            echo_gate.reset();
+            barge_in_checks = 0;
+            since_partial = 0;
+            continue;
+        }
+
+        let samples: Vec<f32> = consumer.pop_iter().collect();
+        let samples = match resampler.process(&samples) {
+            Ok(samples) => samples,
+            Err(e) => {
+                eprintln!("Failed to resample audio: {}", e);
+                continue;
+            }
+        };
+        let reference = playback.take(samples.len());
+        // The gate and VAD get the cleaned audio, since levelling it would make the user talking
+        // over the echo no louder than the echo. Only the utterance sent to STT is levelled
+        let ProcessedMic { cleaned: samples, levelled } = mic_processor.process(&samples, &reference);
+
+        let speaking = assistant_speaking.load(Relaxed);
+        if speaking != was_speaking {
+            was_speaking = speaking;
+            echo_gate.reset();
+            barge_in_checks = 0;
+        }
+        if speaking {
+            // The mic stays open while the assistant talks, only listen for the user talking over it
+            recent.extend_from_slice(&samples);
+            recent_levelled.extend_from_slice(&levelled);
+            let stale = recent.len().saturating_sub(barge_in_window_len);
+            recent.drain(..stale);
+            recent_levelled.drain(..stale);
+
+            // Backends that play through the app say when the audio has actually started. For the
+            // OS voices, which don't, the gate's warmup has to cover the engine starting up
+            let playback_running = !playback.is_tapped() || playback.is_playing();
+            if echo_gate.is_barge_in(&recent, playback_running) {
+                barge_in_checks += 1;
+            } else {
+                barge_in_checks = 0;
+            }
+
+            if barge_in_checks >= BARGE_IN_MIN_CHECKS {
+                println!("User interrupted the assistant!");
+                assistant_speaking.store(false, Relaxed);
+                was_speaking = false;
+                // Only fails once the session has been stopped
+                if block_on(barge_in_tx.send(())).is_err() {
+                    return;
+                }
+                echo_gate.reset();
+                barge_in_checks = 0;
+
+                // What we heard so far is the start of the user's turn
+                vad.begin_speech();
+                vad.process(&recent);
+                fed_to_vad += recent.len();
+                recent.clear();
+                utterance = std::mem::take(&mut recent_levelled);
+            }
+            continue;
+        }
+        recent.clear();
+        recent_levelled.clear();
+
+        utterance.extend_from_slice(&levelled);
+        fed_to_vad += samples.len();
+
+        for event in vad.process(&samples) {
+            match event {
+                VadEvent::SpeechStart { .. } => println!("Speech started"),
+                VadEvent::SpeechEnd { sample } => {
+                    let utterance_start = fed_to_vad - utterance.len();
+                    let end = sample.saturating_sub(utterance_start).min(utterance.len());
+                    let rest = utterance.split_off(end);
+                    let turn = std::mem::replace(&mut utterance, rest);
+                    since_partial = 0;
+
+                    println!("Speech ended! Sending to STT...");
+                    let turn = Utterance { samples: turn, sample_rate: WHISPER_SAMPLE_RATE, is_final: true, truncated: false };
+                    if block_on(audio_tx.send(turn)).is_err() {
+                        return;
                     }
                 }
-                sleep(Duration::from_millis(200));
             }
-        } else {
-            // Else, there is just silence. The samples should be deleted
-            println!("Silence Detected!");
-            sleep(Duration::from_secs(1));
-            // drop half of the samples
-            let half = samples.len() / 2;
-            consumer.pop_iter().take(half).for_each(drop);
         }
-        if should_quit.load(std::sync::atomic::Ordering::Relaxed) {
-            break;
+
+        if let (Some(interval_len), true) = (partial_interval_len, vad.is_speaking()) {
+            since_partial += samples.len();
+            if since_partial >= interval_len {
+                since_partial = 0;
+                // Partials are only a preview, so drop them rather than hold up capture when STT is busy
+                let window_start = utterance.len().saturating_sub(partial_window_len);
+                let partial = Utterance {
+                    samples: utterance[window_start..].to_vec(),
+                    sample_rate: WHISPER_SAMPLE_RATE,
+                    is_final: false,
+                    truncated: window_start > 0,
+                };
+                if audio_tx.try_send(partial).is_err() {
+                    println!("STT is behind, skipping a partial transcript");
+                }
+            }
+        }
+
+        if !vad.is_speaking() {
+            let stale = utterance.len().saturating_sub(pre_roll_len);
+            utterance.drain(..stale);
+        } else if max_utterance_len.map_or(false, |max| utterance.len() >= max) {
+            // Send what we have rather than growing forever, the rest becomes the next turn
+            println!("Speech is too long! Sending to STT...");
+            since_partial = 0;
+            let turn = Utterance { samples: std::mem::take(&mut utterance), sample_rate: WHISPER_SAMPLE_RATE, is_final: true, truncated: false };
+            if block_on(audio_tx.send(turn)).is_err() {
+                return;
+            }
         }
     }
-    // Update messages to be the last message on the messages_update_channel_rx
-    // let session_messages = messages_update_channel_rx.latest().clone();
-    // println!("Messages: {:?}", session_messages);
 }
 
-fn setup_audio() -> Result<(StreamConfig, Consumer<f32, Arc<SharedRb<f32, Vec<MaybeUninit<f32>>>>>, Stream), Error> {
+// Keeps trying until there's a microphone to listen to, so unplugging the only one doesn't end the session
+fn open_input(preferred_device: Option<&str>, stream_failed: &Arc<AtomicBool>, should_quit: &Arc<AtomicBool>) -> Option<(StreamConfig, SampleConsumer, Stream)> {
+    loop {
+        if should_quit.load(Relaxed) {
+            return None;
+        }
+        let input = setup_audio(preferred_device, stream_failed.clone()).and_then(|(config, consumer, stream)| {
+            stream.play()?;
+            Ok((config, consumer, stream))
+        });
+        match input {
+            Ok(input) => return Some(input),
+            Err(e) => {
+                eprintln!("Failed to open the microphone, retrying: {}", e);
+                sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn setup_audio(preferred_device: Option<&str>, stream_failed: Arc<AtomicBool>) -> Result<(StreamConfig, SampleConsumer, Stream), Error> {
     let host = cpal::default_host();
-    let input_device = host
-        .default_input_device()
-        .expect("failed to get default input device");
-    println!("Using default input device: \"{}\"", input_device.name()?);
-    let config = input_device
-        .default_input_config()
-        .expect("Failed to get default input config").config();
-    println!("Default input config: {:?}", config);
-
-    // Top level variables
-    let latency_frames = (LATENCY_MS / 1_000.0) * config.sample_rate.0 as f32;
-    let latency_samples = latency_frames as usize * config.channels as usize;
-    println!("{}", latency_samples);
-
-    // The buffer to share samples
-    let ring = SharedRb::new(latency_samples * 2);
-    let (mut producer, consumer) = ring.split();
-
-    // Setup microphone callback
-    let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
+    let input_device = find_input_device(&host, preferred_device)?;
+    println!("Using input device: \"{}\"", input_device.name()?);
+    let supported_config = input_device.default_input_config()?;
+    let config = supported_config.config();
+    println!("Default input config: {:?} {:?}", config, supported_config.sample_format());
+
+    // The callback mixes every frame down to one sample, so the buffer holds mono audio
+    let ring_samples = ((RING_BUFFER_MS / 1_000.0) * config.sample_rate.0 as f32) as usize;
+    let ring = SharedRb::new(ring_samples);
+    let (producer, consumer) = ring.split();
+
+    let err_fn = move |err: cpal::StreamError| {
+        eprintln!("an error occurred on stream: {}", err);
+        stream_failed.store(true, Relaxed);
+    };
+
+    let input_stream = match supported_config.sample_format() {
+        SampleFormat::I8 => build_input_stream::<i8, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::I16 => build_input_stream::<i16, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::I32 => build_input_stream::<i32, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::I64 => build_input_stream::<i64, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::U8 => build_input_stream::<u8, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::U16 => build_input_stream::<u16, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::U32 => build_input_stream::<u32, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::U64 => build_input_stream::<u64, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::F32 => build_input_stream::<f32, _>(&input_device, &config, producer, err_fn)?,
+        SampleFormat::F64 => build_input_stream::<f64, _>(&input_device, &config, producer, err_fn)?,
+        sample_format => return Err(anyhow!("Unsupported sample format {}", sample_format)),
+    };
+    Ok((config, consumer, input_stream))
+}
+
+fn build_input_stream<T, E>(device: &Device, config: &StreamConfig, mut producer: SampleProducer, err_fn: E) -> Result<Stream, Error>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+    E: FnMut(cpal::StreamError) + Send + 'static,
+{
+    let channels = config.channels as usize;
+    let input_data_fn = move |data: &[T], _: &cpal::InputCallbackInfo| {
         let mut output_fell_behind = false;
-        for &sample in data {
-            if producer.push(sample).is_err() {
+        for frame in data.chunks(channels) {
+            if producer.push(downmix_frame(frame)).is_err() {
                 output_fell_behind = true;
             }
         }
         if output_fell_behind {
-            eprintln!("output stream fell behind: try increasing latency");
+            eprintln!("capture loop fell behind, dropping microphone samples");
         }
     };
 
-    // Build streams.
-    println!(
-        "Attempting to build both streams with f32 samples and `{:?}`.",
-        config
-    );
-    println!("Setup input stream");
-    let input_stream = input_device.build_input_stream(&config, input_data_fn, err_fn, None)?;
-    Ok((config, consumer, input_stream))
+    Ok(device.build_input_stream(config, input_data_fn, err_fn, None)?)
 }
 
 
-pub fn speech_to_text(samples: &Vec<f32>, state: &mut WhisperState) -> String {
+// Picks the most likely language from the first 30 seconds of audio
+pub fn detect_language(samples: &[f32], state: &mut WhisperState) -> Result<String> {
+    state.pcm_to_mel(samples, 8).map_err(|e| anyhow!("Failed to compute mel spectrogram: {:?}", e))?;
+    let probabilities = state.lang_detect(0, 8).map_err(|e| anyhow!("Failed to detect language: {:?}", e))?;
+
+    let (id, _) = probabilities
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .ok_or(anyhow!("No language probabilities"))?;
+    whisper_rs::get_lang_str(id as i32)
+        .map(|code| code.to_string())
+        .ok_or(anyhow!("Unknown language id {}", id))
+}
+
+fn full_params(language: &str) -> FullParams<'_, '_> {
     let mut params = FullParams::new(SamplingStrategy::default());
     params.set_print_progress(false);
     params.set_print_special(false);
     params.set_print_realtime(false);
     params.set_print_timestamps(false);
     params.set_suppress_blank(true);
-    params.set_language(Some("en"));
+    params.set_language(Some(language));
     params.set_token_timestamps(true);
-    params.set_duration_ms(LATENCY_MS as i32);
     params.set_no_context(true);
     params.set_n_threads(8);
 
     //params.set_no_speech_thold(0.3);
     //params.set_split_on_word(true);
+    params
+}
 
-    state
-        .full(params, &*samples)
-        .expect("failed to convert samples");
+// Every segment whisper found, with per-token timing and probabilities. whisper-rs doesn't expose
+// whisper's no-speech probability, so it's estimated from the tokens instead.
+// Audio longer than whisper's 30 second window is transcribed a window at a time, split at pauses
+pub fn speech_to_text(samples: &[f32], state: &mut WhisperState, language: &str) -> Result<Vec<TranscriptSegment>> {
+    let whisper_err = |e: WhisperError| anyhow!("Whisper failed: {:?}", e);
+    let chunk_len = WHISPER_CHUNK_MS * WHISPER_SAMPLE_RATE as usize / 1000;
 
-    let num_tokens = state.full_n_tokens(0).expect("Error");
-    let words = (1..num_tokens - 1)
-        .map(|i| state.full_get_token_text(0, i).expect("Error"))
-        .collect::<String>();
+    let mut segments = vec![];
+    for chunk in split_at_pauses(samples, chunk_len, WHISPER_SAMPLE_RATE) {
+        // whisper's times are relative to the chunk
+        let offset_ms = (chunk.start * 1000 / WHISPER_SAMPLE_RATE as usize) as i64;
+        state.full(full_params(language), &samples[chunk]).map_err(whisper_err)?;
 
-    words
-}
+        for segment in 0..state.full_n_segments().map_err(whisper_err)? {
+            let mut tokens = vec![];
+            for token in 0..state.full_n_tokens(segment).map_err(whisper_err)? {
+                // Tokens that split a multi-byte character aren't valid UTF-8 on their own
+                let text = state.full_get_token_text(segment, token).unwrap_or_default();
+                // Special tokens like [_BEG_] and [_TT_150] aren't words
+                if text.starts_with("[_") || text.starts_with("<|") {
+                    continue;
+                }
+                let data = state.full_get_token_data(segment, token).map_err(whisper_err)?;
+                tokens.push(TranscriptToken {
+                    text,
+                    // whisper times are in 10ms steps
+                    start_ms: offset_ms + data.t0 * 10,
+                    end_ms: offset_ms + data.t1 * 10,
+                    probability: Some(data.p),
+                });
+            }
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
-}
\ No newline at end of file
+            let confidence = if tokens.is_empty() {
+                None
+            } else {
+                Some(tokens.iter().filter_map(|t| t.probability).sum::<f32>() / tokens.len() as f32)
+            };
+            let mut transcript_segment = TranscriptSegment {
+                text: state.full_get_segment_text(segment).map_err(whisper_err)?,
+                start_ms: offset_ms + state.full_get_segment_t0(segment).map_err(whisper_err)? * 10,
+                end_ms: offset_ms + state.full_get_segment_t1(segment).map_err(whisper_err)? * 10,
+                confidence,
+                no_speech_probability: None,
+                tokens,
+            };
+            transcript_segment.no_speech_probability = transcript_segment.estimate_no_speech_probability();
+            segments.push(transcript_segment);
+        }
+    }
+
+    Ok(segments)
+}